@@ -0,0 +1,114 @@
+// Acknowledgement module
+//
+// Tells the API what a device ended up with after applying a configuration, so
+// that the fleet dashboard can show whether it converged. Files are hashed as
+// they are on disk, which also covers a failed apply that got rolled back.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use openssl::sha::sha256;
+
+use crate::fs;
+use crate::schema::OsConfigSchema;
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedState {
+    // Hex encoded SHA-256 of every existing service file by service id and file name
+    pub services: BTreeMap<String, BTreeMap<String, String>>,
+    pub migrated_keys: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    pub error: Option<String>,
+}
+
+pub fn applied_state(
+    schema: &OsConfigSchema,
+    migrated_keys: &[String],
+    generation: Option<u64>,
+    error: Option<&anyhow::Error>,
+) -> AppliedState {
+    let mut services = BTreeMap::new();
+
+    for service in &schema.services {
+        let mut files = BTreeMap::new();
+
+        for (name, config_file) in &service.files {
+            let path = Path::new(&config_file.path);
+
+            if !path.exists() {
+                continue;
+            }
+
+            match fs::read_file_bytes(path) {
+                Ok(contents) => {
+                    files.insert(name.clone(), hex::encode(sha256(&contents)));
+                }
+                Err(err) => warn!("{:#}", err),
+            }
+        }
+
+        services.insert(service.id.clone(), files);
+    }
+
+    AppliedState {
+        services,
+        migrated_keys: migrated_keys.to_vec(),
+        generation,
+        error: error.map(|err| format!("{err:#}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::schema::{ConfigFile, ConfigJsonSchema, Service};
+    use anyhow::anyhow;
+    use tempfile::TempDir;
+
+    #[test]
+    fn hash_service_files() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = test_utils::create_tmp_file(&tmp_dir, "mock-1.conf", "configuration", None);
+
+        let schema = OsConfigSchema {
+            services: vec![Service {
+                id: "mock-1".into(),
+                files: hashmap! {
+                    "mock-1".into() => ConfigFile {
+                        path,
+                        perm: "".into(),
+                    },
+                    "missing".into() => ConfigFile {
+                        path: tmp_dir.path().join("missing.conf").to_str().unwrap().into(),
+                        perm: "".into(),
+                    },
+                },
+                systemd_services: vec![],
+            }],
+            keys: vec![],
+            config: ConfigJsonSchema { whitelist: vec![] },
+            retry: None,
+            signing_key: None,
+        };
+
+        let err = anyhow!("Starting mock.service failed");
+
+        assert_eq!(
+            applied_state(&schema, &["logsEndpoint".into()], Some(2), Some(&err)),
+            AppliedState {
+                services: btreemap! {
+                    "mock-1".into() => btreemap! {
+                        "mock-1".into() =>
+                            "b7d64a9221007dfd5390f7df6cd5b8f3ea4f82faa1237141e35ebf161f5511a1".into()
+                    }
+                },
+                migrated_keys: vec!["logsEndpoint".into()],
+                generation: Some(2),
+                error: Some("Starting mock.service failed".into()),
+            }
+        );
+    }
+}
@@ -1,8 +1,15 @@
-use clap::{command, Arg, ArgMatches, Command};
+use clap::{command, Arg, ArgAction, ArgGroup, ArgMatches, Command};
 
 use std::env;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 
+use anyhow::{Context, Result};
+
+use crate::exit::{ExitStatus, EXIT_CODES_HELP};
+use crate::fs::read_file;
+use crate::retry::RetrySettings;
 use crate::systemd::service_exists;
 
 pub const SUPERVISOR_SERVICE: &str = "balena-supervisor.service";
@@ -12,124 +19,579 @@ const OS_CONFIG_PATH: &str = "/etc/os-config.json";
 const CONFIG_JSON_PATH: &str = "/mnt/boot/config.json";
 const CONFIG_JSON_FLASHER_PATH: &str = "/tmp/config.json";
 const FLASHER_FLAG_PATH: &str = "/mnt/boot/balena-image-flasher";
+const OS_CONFIG_TOML_PATH: &str = "/etc/os-config.toml";
+const STATE_DIR: &str = "/mnt/data/os-config";
+const BOOT_DIR: &str = "/mnt/boot";
 
 const CONFIG_ROUTE_REDEFINE: &str = "CONFIG_ROUTE_REDEFINE";
 const OS_CONFIG_PATH_REDEFINE: &str = "OS_CONFIG_PATH_REDEFINE";
 const CONFIG_JSON_PATH_REDEFINE: &str = "CONFIG_JSON_PATH_REDEFINE";
 const CONFIG_JSON_FLASHER_PATH_REDEFINE: &str = "CONFIG_JSON_FLASHER_PATH_REDEFINE";
 const FLASHER_FLAG_PATH_REDEFINE: &str = "FLASHER_FLAG_PATH_REDEFINE";
+const OS_CONFIG_TOML_PATH_REDEFINE: &str = "OS_CONFIG_TOML_PATH_REDEFINE";
+const STATE_DIR_REDEFINE: &str = "STATE_DIR_REDEFINE";
+const BOOT_DIR_REDEFINE: &str = "BOOT_DIR_REDEFINE";
+const STATE_ROUTE_REDEFINE: &str = "STATE_ROUTE_REDEFINE";
 
 pub enum OsConfigSubcommand {
     GenerateApiKey,
     Update,
     Join,
     Leave,
+    Status,
+    Validate,
+    Rollback,
+    Daemon,
+}
+
+impl OsConfigSubcommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OsConfigSubcommand::GenerateApiKey => "generate-api-key",
+            OsConfigSubcommand::Update => "update",
+            OsConfigSubcommand::Join => "join",
+            OsConfigSubcommand::Leave => "leave",
+            OsConfigSubcommand::Status => "status",
+            OsConfigSubcommand::Validate => "validate",
+            OsConfigSubcommand::Rollback => "rollback",
+            OsConfigSubcommand::Daemon => "daemon",
+        }
+    }
+}
+
+pub enum JsonConfigSource {
+    Argument(String),
+    File(PathBuf),
+    Stdin,
+}
+
+#[derive(Clone, Copy)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Optional defaults file, overridden by environment variables and flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Defaults {
+    config_route: Option<String>,
+    schema: Option<String>,
+    config_json: Option<String>,
+    config_json_flasher: Option<String>,
+    flasher_flag: Option<String>,
+    state_dir: Option<String>,
+    boot_dir: Option<String>,
+    proxy: Option<String>,
+    state_route: Option<String>,
 }
 
 pub struct Args {
     pub subcommand: OsConfigSubcommand,
+    pub output: OutputFormat,
+    pub detailed_exitcodes: bool,
     pub config_route: String,
+    pub state_route: Option<String>,
+    pub proxy: Option<String>,
     pub os_config_path: PathBuf,
     pub config_json_path: PathBuf,
-    pub json_config: Option<String>,
+    pub state_dir: PathBuf,
+    pub boot_dir: PathBuf,
+    pub json_config: Option<JsonConfigSource>,
+    pub dry_run: bool,
+    pub diff: bool,
+    pub offline: bool,
+    pub source: Option<PathBuf>,
+    pub rollback_to: Option<u64>,
+    pub poll_interval: Duration,
+    pub poll_jitter: Duration,
+    pub retry: RetrySettings,
     pub supervisor_exists: bool,
 }
 
-pub fn get_cli_args() -> Args {
+pub fn get_cli_args() -> Result<Args> {
     let matches = command!()
         //        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .after_help(EXIT_CODES_HELP)
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .global(true)
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("Output format of the result, logs go to stderr with `json`"),
+        )
+        .arg(
+            Arg::new("detailed-exitcodes")
+                .long("detailed-exitcodes")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Use distinct exit codes for changed, unchanged and unconfigured"),
+        )
+        .arg(global_path_arg("config-json", "Path of config.json"))
+        .arg(global_path_arg(
+            "config-json-flasher",
+            "Path of config.json on flasher images",
+        ))
+        .arg(global_path_arg(
+            "flasher-flag",
+            "Path of the file flagging a flasher image",
+        ))
+        .arg(global_path_arg(
+            "schema",
+            "Path of the os-config.json schema",
+        ))
+        .arg(global_path_arg(
+            "state-dir",
+            "Directory keeping the applied configuration generations",
+        ))
+        .arg(global_path_arg(
+            "boot-dir",
+            "Boot partition directory holding the system proxy settings and client certificate",
+        ))
+        .arg(
+            Arg::new("config-route")
+                .long("config-route")
+                .global(true)
+                .value_name("ROUTE")
+                .help("API route of the remote configuration"),
+        )
+        .arg(
+            Arg::new("state-route")
+                .long("state-route")
+                .global(true)
+                .value_name("ROUTE")
+                .help("API route the applied configuration state is reported to, e.g. /os/v1/config/state"),
+        )
+        .arg(
+            Arg::new("proxy")
+                .long("proxy")
+                .global(true)
+                .value_name("URL")
+                .help("HTTP or SOCKS5 proxy for the API, instead of the system proxy"),
+        )
         .subcommand(
             Command::new("generate-api-key").about("Generates deviceApiKey for configured device"),
         )
         .subcommand(
             Command::new("update")
-                .about("Apply available configuration updates on a configured device"),
+                .about("Apply available configuration updates on a configured device")
+                .arg(dry_run_arg())
+                .arg(diff_arg())
+                .arg(
+                    Arg::new("offline")
+                        .long("offline")
+                        .action(ArgAction::SetTrue)
+                        .help("Apply the last fetched configuration without contacting the API"),
+                )
+                .arg(source_arg())
+                .args(retry_args()),
         )
         .subcommand(
             Command::new("join")
                 .about("Configure/reconfigure a device")
                 .arg(
                     Arg::new("JSON_CONFIG")
-                        .help("Provisioning JSON configuration")
-                        .required(true)
+                        .help("Provisioning JSON configuration, `-` reads it from stdin")
                         .index(1),
-                ),
+                )
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .value_name("PATH")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Read the provisioning JSON configuration from a file"),
+                )
+                .group(
+                    ArgGroup::new("json-config-source")
+                        .args(["JSON_CONFIG", "file"])
+                        .required(true),
+                )
+                .arg(dry_run_arg())
+                .arg(diff_arg())
+                .arg(source_arg())
+                .args(retry_args()),
         )
         .subcommand(Command::new("leave").about("Deconfigure a device"))
-        .get_matches();
+        .subcommand(Command::new("status").about("Report the configuration state of a device"))
+        .subcommand(
+            Command::new("validate")
+                .about("Check an os-config.json schema and report every problem found")
+                .arg(
+                    Arg::new("PATH")
+                        .help("Path of the schema, defaults to the os-config.json in use")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            Command::new("rollback")
+                .about("Restore a previously applied configuration generation")
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("GENERATION")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Generation to restore, defaults to the one before the current"),
+                ),
+        )
+        .subcommand(
+            Command::new("daemon")
+                .about("Periodically apply available configuration updates")
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("300")
+                        .help("Seconds between configuration update checks"),
+                )
+                .arg(
+                    Arg::new("jitter")
+                        .long("jitter")
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("60")
+                        .help("Maximum random delay added to every interval"),
+                )
+                .arg(source_arg())
+                .args(retry_args()),
+        )
+        .try_get_matches();
 
-    let (subcommand, json_config) = match matches.subcommand() {
-        Some(("generate-api-key", _)) => (OsConfigSubcommand::GenerateApiKey, None),
-        Some(("update", _)) => (OsConfigSubcommand::Update, None),
-        Some(("join", sub_m)) => (OsConfigSubcommand::Join, Some(get_json_config(sub_m))),
-        Some(("leave", _)) => (OsConfigSubcommand::Leave, None),
+    let matches = match matches {
+        Ok(matches) => matches,
+        // Clap exits with 2 on usage errors, which reads as changes applied with
+        // `--detailed-exitcodes`
+        Err(err) if err.use_stderr() => {
+            let _ = err.print();
+            process::exit(ExitStatus::Failure as i32);
+        }
+        Err(err) => err.exit(),
+    };
+
+    let (subcommand, sub_matches) = match matches.subcommand() {
+        Some(("generate-api-key", sub_m)) => (OsConfigSubcommand::GenerateApiKey, sub_m),
+        Some(("update", sub_m)) => (OsConfigSubcommand::Update, sub_m),
+        Some(("join", sub_m)) => (OsConfigSubcommand::Join, sub_m),
+        Some(("leave", sub_m)) => (OsConfigSubcommand::Leave, sub_m),
+        Some(("status", sub_m)) => (OsConfigSubcommand::Status, sub_m),
+        Some(("validate", sub_m)) => (OsConfigSubcommand::Validate, sub_m),
+        Some(("rollback", sub_m)) => (OsConfigSubcommand::Rollback, sub_m),
+        Some(("daemon", sub_m)) => (OsConfigSubcommand::Daemon, sub_m),
         _ => unreachable!(),
     };
 
-    let config_route = get_config_route();
-    let os_config_path = get_os_config_path();
-    let config_json_path = get_config_json_path();
-    let supervisor_exists = service_exists(SUPERVISOR_SERVICE);
+    let output = get_output_format(&matches);
+    let detailed_exitcodes = get_flag(&matches, "detailed-exitcodes");
+
+    let json_config = match subcommand {
+        OsConfigSubcommand::Join => Some(get_json_config(sub_matches)),
+        _ => None,
+    };
+    let dry_run = get_flag(sub_matches, "dry-run");
+    let diff = get_flag(sub_matches, "diff");
+    let offline = get_flag(sub_matches, "offline");
+    let source = get_source(sub_matches);
+    let rollback_to = match sub_matches.try_get_one::<u64>("to") {
+        Ok(Some(generation)) => Some(*generation),
+        _ => None,
+    };
+
+    let poll_interval = get_seconds(sub_matches, "interval");
+    let poll_jitter = get_seconds(sub_matches, "jitter");
+    let retry = get_retry_settings(sub_matches);
+
+    let defaults = read_defaults()?;
 
-    Args {
+    let config_route = get_config_route(&matches, &defaults);
+    let state_route = get_state_route(&matches, &defaults);
+    let proxy = get_proxy(&matches, &defaults);
+    let os_config_path = match sub_matches.try_get_one::<PathBuf>("PATH") {
+        Ok(Some(path)) => path.clone(),
+        _ => get_os_config_path(&matches, &defaults),
+    };
+    let config_json_path = get_config_json_path(&matches, &defaults);
+    let state_dir = get_state_dir(&matches, &defaults);
+    let boot_dir = get_boot_dir(&matches, &defaults);
+
+    // A dry run should not talk to D-Bus at all, so whether the supervisor is there is not known
+    let supervisor_exists = !dry_run && service_exists(SUPERVISOR_SERVICE);
+
+    Ok(Args {
         subcommand,
+        output,
+        detailed_exitcodes,
         config_route,
+        state_route,
+        proxy,
         os_config_path,
         config_json_path,
+        state_dir,
+        boot_dir,
         json_config,
+        dry_run,
+        diff,
+        offline,
+        source,
+        rollback_to,
+        poll_interval,
+        poll_jitter,
+        retry,
         supervisor_exists,
+    })
+}
+
+fn global_path_arg(id: &'static str, help: &'static str) -> Arg {
+    Arg::new(id)
+        .long(id)
+        .global(true)
+        .value_name("PATH")
+        .help(help)
+}
+
+fn dry_run_arg() -> Arg {
+    Arg::new("dry-run")
+        .long("dry-run")
+        .action(ArgAction::SetTrue)
+        .help("Print the changes that would be applied without applying them")
+}
+
+fn diff_arg() -> Arg {
+    Arg::new("diff")
+        .long("diff")
+        .action(ArgAction::SetTrue)
+        .help("Print a diff of the configuration files and config.json changes")
+}
+
+fn source_arg() -> Arg {
+    Arg::new("source").long("source").value_name("SOURCE").help(
+        "Read the service configuration from a file, `file://` URL or directory instead of the API",
+    )
+}
+
+fn retry_args() -> Vec<Arg> {
+    let arg = |id: &'static str, value_name: &'static str, help: &'static str| {
+        Arg::new(id)
+            .long(id)
+            .value_name(value_name)
+            .value_parser(clap::value_parser!(u64))
+            .help(help)
+    };
+
+    vec![
+        arg(
+            "retry-attempts",
+            "COUNT",
+            "Maximum attempts to fetch the configuration, 0 for no limit",
+        ),
+        arg(
+            "retry-duration",
+            "SECONDS",
+            "Maximum time spent retrying, 0 for no limit",
+        ),
+        arg(
+            "connect-timeout",
+            "SECONDS",
+            "Timeout for connecting to the API",
+        ),
+        arg(
+            "read-timeout",
+            "SECONDS",
+            "Timeout for a configuration request once connected",
+        ),
+    ]
+}
+
+fn get_retry_settings(matches: &ArgMatches) -> RetrySettings {
+    let get = |id: &str| match matches.try_get_one::<u64>(id) {
+        Ok(Some(value)) => Some(*value),
+        _ => None,
+    };
+
+    RetrySettings {
+        max_attempts: get("retry-attempts").map(|attempts| attempts.min(u32::MAX as u64) as u32),
+        max_duration: get("retry-duration"),
+        connect_timeout: get("connect-timeout"),
+        read_timeout: get("read-timeout"),
+        ..Default::default()
     }
 }
 
-pub fn get_os_config_path() -> PathBuf {
-    path_buf(&try_redefined(OS_CONFIG_PATH, OS_CONFIG_PATH_REDEFINE))
+fn get_seconds(matches: &ArgMatches, id: &str) -> Duration {
+    match matches.try_get_one::<u64>(id) {
+        Ok(Some(seconds)) => Duration::from_secs(*seconds),
+        _ => Duration::ZERO,
+    }
+}
+
+fn get_os_config_path(matches: &ArgMatches, defaults: &Defaults) -> PathBuf {
+    path_buf(&try_redefined(
+        matches,
+        "schema",
+        OS_CONFIG_PATH_REDEFINE,
+        &defaults.schema,
+        OS_CONFIG_PATH,
+    ))
 }
 
-pub fn get_config_json_path() -> PathBuf {
-    if get_flasher_flag_path().exists() {
-        get_config_json_flasher_path()
+// An explicit `--config-json` is taken as is, even on a flasher image
+fn get_config_json_path(matches: &ArgMatches, defaults: &Defaults) -> PathBuf {
+    if matches.get_one::<String>("config-json").is_none()
+        && get_flasher_flag_path(matches, defaults).exists()
+    {
+        get_config_json_flasher_path(matches, defaults)
     } else {
-        get_config_json_standard_path()
+        get_config_json_standard_path(matches, defaults)
     }
 }
 
-fn get_config_json_standard_path() -> PathBuf {
-    path_buf(&try_redefined(CONFIG_JSON_PATH, CONFIG_JSON_PATH_REDEFINE))
+fn get_config_json_standard_path(matches: &ArgMatches, defaults: &Defaults) -> PathBuf {
+    path_buf(&try_redefined(
+        matches,
+        "config-json",
+        CONFIG_JSON_PATH_REDEFINE,
+        &defaults.config_json,
+        CONFIG_JSON_PATH,
+    ))
 }
 
-fn get_config_json_flasher_path() -> PathBuf {
+fn get_config_json_flasher_path(matches: &ArgMatches, defaults: &Defaults) -> PathBuf {
     path_buf(&try_redefined(
-        CONFIG_JSON_FLASHER_PATH,
+        matches,
+        "config-json-flasher",
         CONFIG_JSON_FLASHER_PATH_REDEFINE,
+        &defaults.config_json_flasher,
+        CONFIG_JSON_FLASHER_PATH,
     ))
 }
 
-fn get_flasher_flag_path() -> PathBuf {
+fn get_flasher_flag_path(matches: &ArgMatches, defaults: &Defaults) -> PathBuf {
     path_buf(&try_redefined(
-        FLASHER_FLAG_PATH,
+        matches,
+        "flasher-flag",
         FLASHER_FLAG_PATH_REDEFINE,
+        &defaults.flasher_flag,
+        FLASHER_FLAG_PATH,
     ))
 }
 
-fn get_json_config(matches: &ArgMatches) -> String {
-    if let Some(contents) = matches.get_one::<String>("JSON_CONFIG") {
-        contents.into()
+fn get_json_config(matches: &ArgMatches) -> JsonConfigSource {
+    if let Some(path) = matches.get_one::<PathBuf>("file") {
+        JsonConfigSource::File(path.clone())
+    } else if let Some(contents) = matches.get_one::<String>("JSON_CONFIG") {
+        if contents == "-" {
+            JsonConfigSource::Stdin
+        } else {
+            JsonConfigSource::Argument(contents.into())
+        }
     } else {
         unreachable!()
     }
 }
 
-fn get_config_route() -> String {
-    try_redefined(CONFIG_ROUTE, CONFIG_ROUTE_REDEFINE)
+fn get_source(matches: &ArgMatches) -> Option<PathBuf> {
+    match matches.try_get_one::<String>("source") {
+        Ok(Some(source)) => Some(path_buf(source.strip_prefix("file://").unwrap_or(source))),
+        _ => None,
+    }
 }
 
-fn try_redefined(default: &str, redefine_env_var: &str) -> String {
-    match env::var(redefine_env_var) {
-        Ok(val) => val,
-        Err(_) => default.to_string(),
+fn get_output_format(matches: &ArgMatches) -> OutputFormat {
+    match matches.get_one::<String>("output").map(String::as_str) {
+        Some("json") => OutputFormat::Json,
+        _ => OutputFormat::Text,
     }
 }
 
+fn get_flag(matches: &ArgMatches, id: &str) -> bool {
+    // Not every subcommand defines every flag
+    matches!(matches.try_get_one::<bool>(id), Ok(Some(true)))
+}
+
+fn get_state_dir(matches: &ArgMatches, defaults: &Defaults) -> PathBuf {
+    path_buf(&try_redefined(
+        matches,
+        "state-dir",
+        STATE_DIR_REDEFINE,
+        &defaults.state_dir,
+        STATE_DIR,
+    ))
+}
+
+// Not derived from the config.json path, which is elsewhere on flasher images
+fn get_boot_dir(matches: &ArgMatches, defaults: &Defaults) -> PathBuf {
+    path_buf(&try_redefined(
+        matches,
+        "boot-dir",
+        BOOT_DIR_REDEFINE,
+        &defaults.boot_dir,
+        BOOT_DIR,
+    ))
+}
+
+fn get_config_route(matches: &ArgMatches, defaults: &Defaults) -> String {
+    try_redefined(
+        matches,
+        "config-route",
+        CONFIG_ROUTE_REDEFINE,
+        &defaults.config_route,
+        CONFIG_ROUTE,
+    )
+}
+
+// There is no built-in route, reporting is off unless one is set
+fn get_state_route(matches: &ArgMatches, defaults: &Defaults) -> Option<String> {
+    matches
+        .get_one::<String>("state-route")
+        .cloned()
+        .or_else(|| env::var(STATE_ROUTE_REDEFINE).ok())
+        .or_else(|| defaults.state_route.clone())
+}
+
+fn get_proxy(matches: &ArgMatches, defaults: &Defaults) -> Option<String> {
+    matches
+        .get_one::<String>("proxy")
+        .cloned()
+        .or_else(|| defaults.proxy.clone())
+}
+
+// Precedence is command line flag, then environment variable, then defaults file, then built-in
+fn try_redefined(
+    matches: &ArgMatches,
+    flag: &str,
+    redefine_env_var: &str,
+    defaults_value: &Option<String>,
+    builtin: &str,
+) -> String {
+    if let Some(val) = matches.get_one::<String>(flag) {
+        return val.clone();
+    }
+
+    if let Ok(val) = env::var(redefine_env_var) {
+        return val;
+    }
+
+    if let Some(val) = defaults_value {
+        return val.clone();
+    }
+
+    builtin.to_string()
+}
+
+fn read_defaults() -> Result<Defaults> {
+    let path =
+        path_buf(&env::var(OS_CONFIG_TOML_PATH_REDEFINE).unwrap_or(OS_CONFIG_TOML_PATH.into()));
+
+    if !path.exists() {
+        return Ok(Defaults::default());
+    }
+
+    let contents = read_file(&path)?;
+
+    toml::from_str(&contents).context(format!("Parsing {path:?} failed"))
+}
+
 fn path_buf(path: &str) -> PathBuf {
     Path::new(path).to_path_buf()
 }
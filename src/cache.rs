@@ -0,0 +1,206 @@
+// Cache module
+//
+// Persists the last applied remote configuration between runs under the state
+// directory. The configuration body is kept as received in
+// `<state dir>/configuration.json`, along with its signature in
+// `configuration.json.sig`, so that it can be verified again and reapplied
+// without connectivity. The cache validators of the response it came with are
+// kept in `<state dir>/validators.json`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::fs;
+use crate::remote::{parse_payload, Payload, RemoteConfiguration, Validators};
+use crate::signature::SigningKey;
+
+const CONFIGURATION_FILE: &str = "configuration.json";
+const SIGNATURE_FILE: &str = "configuration.json.sig";
+const VALIDATORS_FILE: &str = "validators.json";
+
+// The cached body goes through the same signature check as a fetched one, so a
+// configuration that was never signed is not applied from the cache either
+pub fn read_configuration(
+    state_dir: &Path,
+    signing_key: Option<&SigningKey>,
+) -> Option<RemoteConfiguration> {
+    let path = state_dir.join(CONFIGURATION_FILE);
+
+    if !path.exists() {
+        return None;
+    }
+
+    match read_payload(state_dir).and_then(|payload| {
+        parse_payload(&payload, signing_key).context(format!("Parsing {path:?} failed"))
+    }) {
+        Ok(remote_config) => Some(remote_config),
+        Err(err) => {
+            warn!("Ignoring cached configuration: {:#}", err);
+            None
+        }
+    }
+}
+
+// Configuration files may hold secrets, so keep the cache private
+pub fn write_configuration(state_dir: &Path, payload: &Payload) -> Result<()> {
+    ::std::fs::create_dir_all(state_dir).context(format!("Creating {state_dir:?} failed"))?;
+
+    let signature_path = state_dir.join(SIGNATURE_FILE);
+
+    // A stale signature must not be paired with another body
+    if signature_path.exists() {
+        fs::remove_file(&signature_path)?;
+    }
+
+    fs::write_file(
+        &state_dir.join(CONFIGURATION_FILE),
+        &payload.body,
+        Some(0o600),
+    )?;
+
+    match payload.signature {
+        Some(ref signature) => fs::write_file(&signature_path, signature, Some(0o600)),
+        None => Ok(()),
+    }
+}
+
+pub fn clear_configuration(state_dir: &Path) -> Result<()> {
+    for name in [CONFIGURATION_FILE, SIGNATURE_FILE] {
+        let path = state_dir.join(name);
+
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_payload(state_dir: &Path) -> Result<Payload> {
+    let signature_path = state_dir.join(SIGNATURE_FILE);
+
+    let signature = if signature_path.exists() {
+        Some(fs::read_file(&signature_path)?)
+    } else {
+        None
+    };
+
+    Ok(Payload {
+        body: fs::read_file(&state_dir.join(CONFIGURATION_FILE))?,
+        signature,
+    })
+}
+
+// A missing or unreadable file just means the next fetch is unconditional
+pub fn read_validators(state_dir: &Path) -> Option<Validators> {
+    read_json(&validators_path(state_dir), "cached validators")
+}
+
+pub fn write_validators(state_dir: &Path, validators: &Validators) -> Result<()> {
+    ::std::fs::create_dir_all(state_dir).context(format!("Creating {state_dir:?} failed"))?;
+
+    fs::write_file(
+        &validators_path(state_dir),
+        &serde_json::to_string_pretty(validators)?,
+        None,
+    )
+}
+
+pub fn clear_validators(state_dir: &Path) -> Result<()> {
+    let path = validators_path(state_dir);
+
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+
+    Ok(())
+}
+
+fn validators_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(VALIDATORS_FILE)
+}
+
+// The cache is only an optimization, so a corrupt file is ignored rather than fatal
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path, what: &str) -> Option<T> {
+    if !path.exists() {
+        return None;
+    }
+
+    match fs::read_file(path).and_then(|contents| {
+        serde_json::from_str(&contents).context(format!("Parsing {path:?} failed"))
+    }) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            warn!("Ignoring {}: {:#}", what, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::signature::parse_signing_key;
+    use tempfile::TempDir;
+
+    #[test]
+    fn write_read_and_clear_validators() {
+        let tmp_dir = TempDir::new().unwrap();
+        let state_dir = tmp_dir.path().join("os-config");
+
+        assert_eq!(read_validators(&state_dir), None);
+
+        let validators = Validators {
+            url: "https://api.balena-cloud.com/os/v1/config".into(),
+            etag: Some("\"abc\"".into()),
+            last_modified: None,
+        };
+
+        write_validators(&state_dir, &validators).unwrap();
+        assert_eq!(read_validators(&state_dir), Some(validators));
+
+        clear_validators(&state_dir).unwrap();
+        assert_eq!(read_validators(&state_dir), None);
+    }
+
+    #[test]
+    fn ignore_corrupt_configuration() {
+        let tmp_dir = TempDir::new().unwrap();
+        let state_dir = tmp_dir.path();
+
+        ::std::fs::write(state_dir.join(CONFIGURATION_FILE), "{").unwrap();
+
+        assert_eq!(read_configuration(state_dir, None), None);
+    }
+
+    #[test]
+    fn verify_cached_configuration() {
+        let tmp_dir = TempDir::new().unwrap();
+        let state_dir = tmp_dir.path();
+        let signing_key = parse_signing_key(test_utils::signing_key().as_bytes()).unwrap();
+
+        let body = r#"{"services": {}, "config": {"overrides": {}}}"#;
+
+        let unsigned = Payload {
+            body: body.into(),
+            signature: None,
+        };
+
+        write_configuration(state_dir, &unsigned).unwrap();
+        assert!(read_configuration(state_dir, None).is_some());
+        assert_eq!(read_configuration(state_dir, Some(&signing_key)), None);
+
+        let signed = Payload {
+            body: body.into(),
+            signature: Some(test_utils::sign_config(body)),
+        };
+
+        write_configuration(state_dir, &signed).unwrap();
+        assert!(read_configuration(state_dir, Some(&signing_key)).is_some());
+
+        write_configuration(state_dir, &unsigned).unwrap();
+        assert_eq!(read_configuration(state_dir, Some(&signing_key)), None);
+    }
+}
@@ -3,10 +3,15 @@ use std::path::Path;
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 
+use openssl::x509::X509;
+
 use serde_json::{Map, Value};
 
+use crate::exit::Failure;
 use crate::fs::{read_file, write_file};
+use crate::pin::get_api_pins;
 use crate::random::fill_random;
+use crate::signature::{parse_signing_key, SigningKey};
 
 use anyhow::{bail, Context, Result};
 
@@ -70,14 +75,13 @@ fn get_device_type(config_json: &ConfigMap) -> Result<Option<String>> {
     }
 }
 
-pub fn get_root_certificate(config_json: &ConfigMap) -> Result<Option<reqwest::Certificate>> {
+pub fn get_root_certificate(config_json: &ConfigMap) -> Result<Option<X509>> {
     if let Some(value) = config_json.get("balenaRootCA") {
         if let Some(root_certificate) = value.as_str() {
             let decoded = STANDARD
                 .decode(root_certificate)
                 .context("`balenaRootCA` base64 decoding failed")?;
-            let cert = reqwest::Certificate::from_pem(&decoded)
-                .context("Not a valid PEM encoded certificate")?;
+            let cert = X509::from_pem(&decoded).context("Not a valid PEM encoded certificate")?;
             Ok(Some(cert))
         } else {
             bail!("`balenaRootCA` should be a string")
@@ -87,6 +91,71 @@ pub fn get_root_certificate(config_json: &ConfigMap) -> Result<Option<reqwest::C
     }
 }
 
+pub fn get_signing_key(config_json: &ConfigMap) -> Result<Option<SigningKey>> {
+    if let Some(value) = config_json.get("balenaConfigSigningKey") {
+        if let Some(signing_key) = value.as_str() {
+            let decoded = STANDARD
+                .decode(signing_key)
+                .context("`balenaConfigSigningKey` base64 decoding failed")?;
+            Ok(Some(parse_signing_key(&decoded)?))
+        } else {
+            bail!("`balenaConfigSigningKey` should be a string")
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+pub struct ApiMirror {
+    pub api_endpoint: String,
+    pub root_certificate: Option<X509>,
+    pub pins: Vec<String>,
+}
+
+// Mirrors are listed either as bare endpoints or as objects with an `apiEndpoint` and an
+// optional `balenaRootCA` and `balenaApiPins` of their own
+pub fn get_api_mirrors(config_json: &ConfigMap) -> Result<Vec<ApiMirror>> {
+    let Some(value) = config_json.get("apiMirrors") else {
+        return Ok(vec![]);
+    };
+
+    let Some(values) = value.as_array() else {
+        bail!("`apiMirrors` should be an array")
+    };
+
+    let mut mirrors = Vec::new();
+
+    for value in values {
+        let mirror = match value {
+            Value::String(api_endpoint) => ApiMirror {
+                api_endpoint: api_endpoint.clone(),
+                root_certificate: None,
+                pins: vec![],
+            },
+            Value::Object(mirror) => ApiMirror {
+                api_endpoint: get_api_endpoint(mirror)?
+                    .context("`apiMirrors` entry has no `apiEndpoint`")?,
+                root_certificate: get_root_certificate(mirror)?,
+                pins: get_api_pins(mirror)?,
+            },
+            _ => bail!("`apiMirrors` entries should be strings or objects"),
+        };
+
+        if !mirror.api_endpoint.starts_with("https://")
+            && !mirror.api_endpoint.starts_with("http://")
+        {
+            bail!(
+                "`apiMirrors` entry `{}` should be an http(s) URL",
+                mirror.api_endpoint
+            );
+        }
+
+        mirrors.push(mirror);
+    }
+
+    Ok(mirrors)
+}
+
 fn define_api_key(config_json: &mut ConfigMap, json_config: &ConfigMap) -> Result<()> {
     store_api_key(config_json)?;
 
@@ -138,6 +207,8 @@ fn insert_api_key(config_json: &mut ConfigMap, api_key: &str, api_endpoint: &str
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum GenerateApiKeyResult {
     UnconfiguredDevice,
     GeneratedAlready,
@@ -197,7 +268,10 @@ fn set_api_key(config_json: &mut ConfigMap, api_key: &str, api_endpoint: &str) -
     insert_api_key(config_json, api_key, api_endpoint)
 }
 
-fn get_api_key_for_endpoint(config_json: &ConfigMap, api_endpoint: &str) -> Result<Option<String>> {
+pub fn get_api_key_for_endpoint(
+    config_json: &ConfigMap,
+    api_endpoint: &str,
+) -> Result<Option<String>> {
     if let Some(keys_value) = config_json.get("deviceApiKeys") {
         if let Some(keys) = keys_value.as_object() {
             if let Some(value) = keys.get(&strip_api_endpoint(api_endpoint)) {
@@ -217,6 +291,32 @@ fn get_api_key_for_endpoint(config_json: &ConfigMap, api_endpoint: &str) -> Resu
     }
 }
 
+pub fn get_stored_api_keys(config_json: &ConfigMap) -> Result<Vec<(String, String)>> {
+    let mut stored = Vec::new();
+
+    if let Some(keys_value) = config_json.get("deviceApiKeys") {
+        if let Some(keys) = keys_value.as_object() {
+            for (endpoint, value) in keys {
+                if let Some(api_key) = value.as_str() {
+                    stored.push((endpoint.clone(), api_key.to_string()));
+                } else {
+                    bail!("`deviceApiKey` should be a string")
+                }
+            }
+        } else {
+            bail!("`deviceApiKeys` should be a map")
+        }
+    }
+
+    stored.sort();
+
+    Ok(stored)
+}
+
+pub fn redact_api_key(api_key: &str) -> String {
+    format!("{api_key:.7}...")
+}
+
 pub fn read_config_json(path: &Path) -> Result<ConfigMap> {
     read_json_object_file(path).context(format!("Reading {path:?} failed"))
 }
@@ -238,7 +338,7 @@ fn json_object_from_string(contents: &str) -> Result<ConfigMap> {
 }
 
 pub fn write_config_json(path: &Path, map: &ConfigMap) -> Result<()> {
-    write_json_object_file(path, map).context(format!("Writing {path:?} failed"))
+    write_json_object_file(path, map).context(Failure::Write(format!("Writing {path:?} failed")))
 }
 
 fn write_json_object_file(path: &Path, map: &ConfigMap) -> Result<()> {
@@ -551,6 +651,51 @@ mod tests {
         get_root_certificate(&config_json).unwrap();
     }
 
+    /*******************************************************************************
+     * get_api_mirrors
+     */
+    #[test]
+    fn get_api_mirrors_returns_mirrors() {
+        let (_pkey, cert) = test_utils::generate_self_signed_cert();
+        let config_json = serde_json::from_value::<ConfigMap>(json!({
+            "apiMirrors": [
+                "https://api.mirror1.com",
+                {
+                    "apiEndpoint": "https://api.mirror2.com",
+                    "balenaRootCA": test_utils::cert_for_json(&cert),
+                    "balenaApiPins": ["sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="]
+                }
+            ]
+        }))
+        .unwrap();
+
+        let mirrors = get_api_mirrors(&config_json).unwrap();
+        assert_eq!(mirrors.len(), 2);
+        assert_eq!(mirrors[0].api_endpoint, "https://api.mirror1.com");
+        assert!(mirrors[0].root_certificate.is_none());
+        assert_eq!(mirrors[1].api_endpoint, "https://api.mirror2.com");
+        assert!(mirrors[1].root_certificate.is_some());
+        assert_eq!(
+            mirrors[1].pins,
+            vec!["47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="]
+        );
+    }
+
+    #[test]
+    fn get_api_mirrors_returns_empty_if_not_exists() {
+        assert!(get_api_mirrors(&Map::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "`apiMirrors` entry `api.mirror.com` should be an http(s) URL")]
+    fn get_api_mirrors_errors_if_not_url() {
+        let config_json = serde_json::from_value::<ConfigMap>(json!({
+            "apiMirrors": ["api.mirror.com"]
+        }))
+        .unwrap();
+        get_api_mirrors(&config_json).unwrap();
+    }
+
     /*******************************************************************************
      * store_api_key
      */
@@ -723,6 +868,56 @@ mod tests {
         );
     }
 
+    /*******************************************************************************
+     * get_stored_api_keys
+     */
+    #[test]
+    fn get_stored_api_keys_returns_sorted_keys() {
+        let config_json = serde_json::from_str(
+            r#"
+            {
+                "deviceApiKeys": {
+                    "api.endpoint2.com": "key2",
+                    "api.endpoint.com": "key1"
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_stored_api_keys(&config_json).unwrap(),
+            vec![
+                ("api.endpoint.com".to_string(), "key1".to_string()),
+                ("api.endpoint2.com".to_string(), "key2".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn get_stored_api_keys_returns_empty_if_no_key_map() {
+        let config_json = serde_json::from_str(
+            r#"
+            {}
+            "#,
+        )
+        .unwrap();
+        assert!(get_stored_api_keys(&config_json).unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = r#"`deviceApiKeys` should be a map"#)]
+    fn get_stored_api_keys_errors_if_malformed_key_map() {
+        let config_json = serde_json::from_str(
+            r#"
+            {
+                "deviceApiKeys": "malformed"
+            }
+            "#,
+        )
+        .unwrap();
+        get_stored_api_keys(&config_json).unwrap();
+    }
+
     /*******************************************************************************
      * read_config_json
      */
@@ -0,0 +1,119 @@
+// Daemon module
+//
+// Runs the `update` reconfiguration periodically instead of relying on an
+// external timer. The schema is read once on start, SIGHUP triggers an
+// immediate check and SIGTERM or SIGINT stop the daemon between checks, or
+// abandon a check that is still retrying the fetch or has not applied yet.
+
+use std::cmp::min;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::args::Args;
+use crate::config_json::read_config_json;
+use crate::join::reconfigure;
+use crate::notify::{notify, watchdog_interval};
+use crate::random::random_duration;
+use crate::report::Report;
+use crate::schema::{read_os_config_schema, OsConfigSchema};
+use crate::signal;
+
+// How often signals and the watchdog are serviced while waiting
+const WAIT_STEP: Duration = Duration::from_millis(250);
+
+pub fn daemon(args: &Args) -> Result<Report> {
+    let schema = read_os_config_schema(&args.os_config_path)?;
+
+    signal::install_handlers()?;
+
+    let watchdog = watchdog_interval();
+
+    notify("READY=1");
+
+    info!(
+        "Checking for configuration updates every {}s",
+        args.poll_interval.as_secs()
+    );
+
+    while !signal::should_terminate() {
+        check_with_watchdog(args, &schema, watchdog);
+
+        let wait = args.poll_interval + random_duration(args.poll_jitter);
+
+        wait_for_next_check(wait, watchdog);
+    }
+
+    info!("Shutting down...");
+
+    notify("STOPPING=1");
+
+    Ok(Report::new("daemon"))
+}
+
+// A failed check is retried on the next interval instead of stopping the daemon
+fn check(args: &Args, schema: &OsConfigSchema) {
+    let result = read_config_json(&args.config_json_path)
+        .and_then(|mut config_json| reconfigure(args, schema, &mut config_json, false));
+
+    if let Err(err) = result {
+        error!("Checking for configuration updates failed: {:#}", err);
+    }
+}
+
+// A check may take longer than the watchdog timeout while the fetch is retried, so the
+// watchdog is serviced from a separate thread until the check returns
+fn check_with_watchdog(args: &Args, schema: &OsConfigSchema, watchdog: Option<Duration>) {
+    let Some(interval) = watchdog else {
+        check(args, schema);
+        return;
+    };
+
+    let (done, finished) = mpsc::channel::<()>();
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            notify("WATCHDOG=1");
+
+            while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(interval) {
+                notify("WATCHDOG=1");
+            }
+        });
+
+        check(args, schema);
+
+        drop(done);
+    });
+}
+
+fn wait_for_next_check(wait: Duration, watchdog: Option<Duration>) {
+    let deadline = Instant::now() + wait;
+    let mut last_ping = None;
+
+    loop {
+        if signal::should_terminate() {
+            return;
+        }
+
+        if signal::take_recheck() {
+            info!("SIGHUP received, checking now");
+            return;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+
+        if let Some(interval) = watchdog {
+            if last_ping.map_or(true, |last: Instant| now - last >= interval) {
+                notify("WATCHDOG=1");
+                last_ping = Some(now);
+            }
+        }
+
+        thread::sleep(min(WAIT_STEP, deadline - now));
+    }
+}
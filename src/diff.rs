@@ -0,0 +1,357 @@
+// Diff module
+//
+// Provides unified diffs of configuration file contents and key-level diffs
+// of config.json, so that changes pushed from /os/vX/config can be audited.
+
+use std::cmp::max;
+
+use serde_json::Value;
+
+use crate::config_json::ConfigMap;
+
+const CONTEXT_LINES: usize = 3;
+
+// Largest LCS table built for the changed lines, about 32 MiB; bigger
+// changes are only summarized, like binary files
+const MAX_LCS_CELLS: usize = 1 << 22;
+
+// config.json keys whose values should never end up in logs
+const SECRET_KEYS: &[&str] = &["apiKey", "deviceApiKey", "deviceApiKeys", "balenaClientKey"];
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, PartialEq)]
+enum Edit<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+pub fn unified_diff(current: &str, future: &str, label: &str) -> Vec<String> {
+    let current_lines = current.split_inclusive('\n').collect::<Vec<_>>();
+    let future_lines = future.split_inclusive('\n').collect::<Vec<_>>();
+
+    let Some(edits) = diff_lines(&current_lines, &future_lines) else {
+        return vec![format!(
+            "File {label} differs, too many changed lines to diff"
+        )];
+    };
+
+    let changes = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Equal(_)))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    if changes.is_empty() {
+        return vec![];
+    }
+
+    let mut output = vec![format!("--- {label}"), format!("+++ {label}")];
+
+    let mut group_start = 0;
+    for i in 1..=changes.len() {
+        if i == changes.len() || changes[i] - changes[i - 1] > 2 * CONTEXT_LINES {
+            let start = changes[group_start].saturating_sub(CONTEXT_LINES);
+            let end = (changes[i - 1] + CONTEXT_LINES + 1).min(edits.len());
+            output.extend(hunk(&edits, start, end));
+            group_start = i;
+        }
+    }
+
+    output
+}
+
+fn hunk(edits: &[Edit], start: usize, end: usize) -> Vec<String> {
+    let current_before = edits[..start]
+        .iter()
+        .filter(|edit| !matches!(edit, Edit::Insert(_)))
+        .count();
+    let future_before = edits[..start]
+        .iter()
+        .filter(|edit| !matches!(edit, Edit::Delete(_)))
+        .count();
+
+    let current_count = edits[start..end]
+        .iter()
+        .filter(|edit| !matches!(edit, Edit::Insert(_)))
+        .count();
+    let future_count = edits[start..end]
+        .iter()
+        .filter(|edit| !matches!(edit, Edit::Delete(_)))
+        .count();
+
+    let mut lines = vec![format!(
+        "@@ -{} +{} @@",
+        hunk_range(current_before, current_count),
+        hunk_range(future_before, future_count)
+    )];
+
+    for edit in &edits[start..end] {
+        let (prefix, line) = match edit {
+            Edit::Equal(line) => (' ', line),
+            Edit::Delete(line) => ('-', line),
+            Edit::Insert(line) => ('+', line),
+        };
+
+        if let Some(line) = line.strip_suffix('\n') {
+            lines.push(format!("{prefix}{line}"));
+        } else {
+            lines.push(format!("{prefix}{line}"));
+            lines.push("\\ No newline at end of file".into());
+        }
+    }
+
+    lines
+}
+
+fn hunk_range(before: usize, count: usize) -> String {
+    if count == 0 {
+        format!("{before},0")
+    } else if count == 1 {
+        format!("{}", before + 1)
+    } else {
+        format!("{},{}", before + 1, count)
+    }
+}
+
+fn diff_lines<'a>(current: &[&'a str], future: &[&'a str]) -> Option<Vec<Edit<'a>>> {
+    let prefix = current
+        .iter()
+        .zip(future)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = current[prefix..]
+        .iter()
+        .rev()
+        .zip(future[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let current_middle = &current[prefix..current.len() - suffix];
+    let future_middle = &future[prefix..future.len() - suffix];
+
+    let n = current_middle.len();
+    let m = future_middle.len();
+
+    match (n + 1).checked_mul(m + 1) {
+        Some(cells) if cells <= MAX_LCS_CELLS => {}
+        _ => return None,
+    }
+
+    // lcs[i][j] is the longest common subsequence of current_middle[i..] and future_middle[j..]
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if current_middle[i] == future_middle[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut edits = current[..prefix]
+        .iter()
+        .map(|line| Edit::Equal(line))
+        .collect::<Vec<_>>();
+
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if current_middle[i] == future_middle[j] {
+            edits.push(Edit::Equal(current_middle[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            edits.push(Edit::Delete(current_middle[i]));
+            i += 1;
+        } else {
+            edits.push(Edit::Insert(future_middle[j]));
+            j += 1;
+        }
+    }
+    edits.extend(current_middle[i..].iter().map(|line| Edit::Delete(line)));
+    edits.extend(future_middle[j..].iter().map(|line| Edit::Insert(line)));
+
+    edits.extend(
+        current[current.len() - suffix..]
+            .iter()
+            .map(|line| Edit::Equal(line)),
+    );
+
+    Some(edits)
+}
+
+pub fn changed_keys(current: &ConfigMap, future: &ConfigMap) -> Vec<String> {
+    let mut keys = current
+        .keys()
+        .chain(future.keys())
+        .filter(|key| current.get(*key) != future.get(*key))
+        .cloned()
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+pub fn config_json_diff(current: &ConfigMap, future: &ConfigMap, label: &str) -> Vec<String> {
+    let mut changes = vec![];
+
+    for key in changed_keys(current, future) {
+        let key = &key as &str;
+        match (current.get(key), future.get(key)) {
+            (Some(current_value), Some(future_value)) => {
+                changes.push(format!("-{}: {}", key, display_value(key, current_value)));
+                changes.push(format!("+{}: {}", key, display_value(key, future_value)));
+            }
+            (Some(current_value), None) => {
+                changes.push(format!("-{}: {}", key, display_value(key, current_value)));
+            }
+            (None, Some(future_value)) => {
+                changes.push(format!("+{}: {}", key, display_value(key, future_value)));
+            }
+            _ => {}
+        }
+    }
+
+    if changes.is_empty() {
+        return changes;
+    }
+
+    let mut output = vec![format!("--- {label}"), format!("+++ {label}")];
+    output.extend(changes);
+    output
+}
+
+fn display_value(key: &str, value: &Value) -> String {
+    if SECRET_KEYS.contains(&key) {
+        REDACTED.into()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diff_identical_contents() {
+        assert!(unified_diff("a\nb\n", "a\nb\n", "file").is_empty());
+    }
+
+    #[test]
+    fn unified_diff_changed_line() {
+        let current = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let future = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n";
+        assert_eq!(
+            unified_diff(current, future, "file"),
+            vec![
+                "--- file",
+                "+++ file",
+                "@@ -2,7 +2,7 @@",
+                " 2",
+                " 3",
+                " 4",
+                "-5",
+                "+five",
+                " 6",
+                " 7",
+                " 8",
+            ]
+        );
+    }
+
+    #[test]
+    fn unified_diff_separate_hunks() {
+        let current = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let future = "one\n2\n3\n4\n5\n6\n7\n8\n9\nten\n";
+        assert_eq!(
+            unified_diff(current, future, "file"),
+            vec![
+                "--- file",
+                "+++ file",
+                "@@ -1,4 +1,4 @@",
+                "-1",
+                "+one",
+                " 2",
+                " 3",
+                " 4",
+                "@@ -7,4 +7,4 @@",
+                " 7",
+                " 8",
+                " 9",
+                "-10",
+                "+ten",
+            ]
+        );
+    }
+
+    #[test]
+    fn unified_diff_new_file() {
+        assert_eq!(
+            unified_diff("", "a\nb", "file"),
+            vec![
+                "--- file",
+                "+++ file",
+                "@@ -0,0 +1,2 @@",
+                "+a",
+                "+b",
+                "\\ No newline at end of file",
+            ]
+        );
+    }
+
+    #[test]
+    fn unified_diff_too_many_changes() {
+        let current = "a\n".repeat(3000);
+        let future = "b\n".repeat(3000);
+        assert_eq!(
+            unified_diff(&current, &future, "file"),
+            vec!["File file differs, too many changed lines to diff"]
+        );
+
+        // Unchanged lines around the changes do not count towards the limit
+        let current = format!("{}a\n", "x\n".repeat(100_000));
+        let future = format!("{}b\n", "x\n".repeat(100_000));
+        assert_eq!(unified_diff(&current, &future, "file").len(), 8);
+    }
+
+    #[test]
+    fn config_json_diff_masks_secrets() {
+        let current = serde_json::from_str(
+            r#"
+            {
+                "deviceApiKey": "key1",
+                "persistentLogging": false,
+                "hostname": "balena"
+            }
+            "#,
+        )
+        .unwrap();
+        let future = serde_json::from_str(
+            r#"
+            {
+                "deviceApiKey": "key2",
+                "persistentLogging": true,
+                "logsEndpoint": "https://logs.balenadev.io"
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config_json_diff(&current, &future, "config.json"),
+            vec![
+                "--- config.json",
+                "+++ config.json",
+                "-deviceApiKey: <redacted>",
+                "+deviceApiKey: <redacted>",
+                "-hostname: \"balena\"",
+                "+logsEndpoint: \"https://logs.balenadev.io\"",
+                "-persistentLogging: false",
+                "+persistentLogging: true",
+            ]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// Exit status module
+//
+// Maps subcommand outcomes and error classes to process exit codes. Failure
+// codes are always used. Outcome codes other than 0 are only used with
+// `--detailed-exitcodes`, so that existing units keep treating every
+// successful run as success. With `--dry-run` the changes code means that
+// there are changes to apply, so a check can be scripted without applying.
+
+use std::fmt;
+use std::process::ExitCode;
+
+use crate::report::Report;
+
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+   0  Success (no changes with --detailed-exitcodes)
+   1  Unclassified failure or invalid arguments
+   2  Changes applied, or pending with --dry-run (with --detailed-exitcodes)
+   3  Unconfigured device (with --detailed-exitcodes)
+  10  Fetching the remote configuration failed
+  11  Reading or validating the os-config.json schema failed
+  12  Communicating with systemd failed
+  13  Writing or removing a file failed";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitStatus {
+    Success = 0,
+    Failure = 1,
+    Changed = 2,
+    Unconfigured = 3,
+    FetchFailure = 10,
+    SchemaFailure = 11,
+    SystemdFailure = 12,
+    WriteFailure = 13,
+}
+
+impl ExitStatus {
+    pub fn from_report(report: &Report, detailed: bool) -> Self {
+        if !report.problems.is_empty() {
+            ExitStatus::SchemaFailure
+        } else if !detailed {
+            ExitStatus::Success
+        } else if report.unconfigured {
+            ExitStatus::Unconfigured
+        } else if !report.written_files.is_empty() || !report.deleted_files.is_empty() {
+            ExitStatus::Changed
+        } else {
+            ExitStatus::Success
+        }
+    }
+
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<Failure>() {
+            Some(Failure::Fetch(_)) => ExitStatus::FetchFailure,
+            Some(Failure::Schema(_)) => ExitStatus::SchemaFailure,
+            Some(Failure::Systemd(_)) => ExitStatus::SystemdFailure,
+            Some(Failure::Write(_)) => ExitStatus::WriteFailure,
+            None => ExitStatus::Failure,
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+// Error context that classifies a failure for the exit code
+#[derive(Debug)]
+pub enum Failure {
+    Fetch(String),
+    Schema(String),
+    Systemd(String),
+    Write(String),
+}
+
+impl Failure {
+    pub fn class(&self) -> &'static str {
+        match self {
+            Failure::Fetch(_) => "fetch",
+            Failure::Schema(_) => "schema",
+            Failure::Systemd(_) => "systemd",
+            Failure::Write(_) => "write",
+        }
+    }
+}
+
+// Class of the failure reported with `--output json`, following `from_error`
+pub fn failure_class(err: &anyhow::Error) -> &'static str {
+    err.downcast_ref::<Failure>()
+        .map_or("unclassified", Failure::class)
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Fetch(message)
+            | Failure::Schema(message)
+            | Failure::Systemd(message)
+            | Failure::Write(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+
+    #[test]
+    fn from_error_unclassified() {
+        let err = anyhow!("Something failed");
+        assert_eq!(ExitStatus::from_error(&err), ExitStatus::Failure);
+        assert_eq!(failure_class(&err), "unclassified");
+    }
+
+    #[test]
+    fn from_error_finds_nested_failure() {
+        let err = anyhow!("Connection refused")
+            .context(Failure::Fetch("Fetching configuration failed".into()))
+            .context("Reconfiguring failed");
+        assert_eq!(ExitStatus::from_error(&err), ExitStatus::FetchFailure);
+        assert_eq!(failure_class(&err), "fetch");
+    }
+
+    #[test]
+    fn from_error_prefers_outermost_failure() {
+        let err = anyhow!("Read-only file system")
+            .context(Failure::Write("Writing \"/tmp/a\" failed".into()))
+            .context(Failure::Systemd("Starting a.service failed".into()));
+        assert_eq!(ExitStatus::from_error(&err), ExitStatus::SystemdFailure);
+        assert_eq!(err.to_string(), "Starting a.service failed");
+        assert_eq!(failure_class(&err), "systemd");
+    }
+
+    #[test]
+    fn from_report_without_detailed_exit_codes() {
+        let mut report = Report::new("update");
+        report.unconfigured = true;
+        assert_eq!(ExitStatus::from_report(&report, false), ExitStatus::Success);
+    }
+
+    #[test]
+    fn from_report_with_detailed_exit_codes() {
+        let mut report = Report::new("update");
+        assert_eq!(ExitStatus::from_report(&report, true), ExitStatus::Success);
+
+        report.written_files.push("/mnt/boot/config.json".into());
+        assert_eq!(ExitStatus::from_report(&report, true), ExitStatus::Changed);
+
+        report.dry_run = true;
+        assert_eq!(ExitStatus::from_report(&report, true), ExitStatus::Changed);
+
+        let mut report = Report::new("leave");
+        report.unconfigured = true;
+        assert_eq!(
+            ExitStatus::from_report(&report, true),
+            ExitStatus::Unconfigured
+        );
+    }
+
+    #[test]
+    fn from_report_with_schema_problems() {
+        let mut report = Report::new("validate");
+        report.problems.push("keys: missing field".into());
+        assert_eq!(
+            ExitStatus::from_report(&report, false),
+            ExitStatus::SchemaFailure
+        );
+    }
+}
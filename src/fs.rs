@@ -1,3 +1,4 @@
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::u32;
 
@@ -6,27 +7,46 @@ use fatrw::write::write_file as fatrw_write_file;
 
 use anyhow::{Context, Result};
 
+use crate::exit::Failure;
+
 pub fn read_file(path: &Path) -> Result<String> {
-    Ok(String::from_utf8(fatrw_read_file(path, false).context(
-        format!("Reading {:?} failed", path.to_path_buf()),
-    )?)?)
+    Ok(String::from_utf8(read_file_bytes(path)?)?)
+}
+
+pub fn read_file_bytes(path: &Path) -> Result<Vec<u8>> {
+    fatrw_read_file(path, false).context(format!("Reading {:?} failed", path.to_path_buf()))
 }
 
 pub fn write_file(path: &Path, contents: &str, mode: Option<u32>) -> Result<()> {
-    fatrw_write_file(path, contents.as_bytes(), mode, false)
-        .context(format!("Writing {:?} failed", path.to_path_buf()))
+    write_file_bytes(path, contents.as_bytes(), mode)
+}
+
+pub fn write_file_bytes(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<()> {
+    fatrw_write_file(path, contents, mode, false).context(Failure::Write(format!(
+        "Writing {:?} failed",
+        path.to_path_buf()
+    )))
 }
 
 pub fn parse_mode(mode: &str) -> Result<Option<u32>> {
     if !mode.is_empty() {
-        Ok(Some(u32::from_str_radix(mode, 8).context(format!(
-            "Parsing permission mode `{mode}` failed"
-        ))?))
+        Ok(Some(u32::from_str_radix(mode, 8).context(
+            Failure::Schema(format!("Parsing permission mode `{mode}` failed")),
+        )?))
     } else {
         Ok(None)
     }
 }
 
 pub fn remove_file(path: &Path) -> Result<()> {
-    ::std::fs::remove_file(path).context(format!("Removing {:?} failed", path.to_path_buf()))
+    ::std::fs::remove_file(path).context(Failure::Write(format!(
+        "Removing {:?} failed",
+        path.to_path_buf()
+    )))
+}
+
+pub fn get_mode(path: &Path) -> Result<u32> {
+    let metadata =
+        ::std::fs::metadata(path).context(format!("Reading {:?} failed", path.to_path_buf()))?;
+    Ok(metadata.permissions().mode() & 0o7777)
 }
@@ -5,22 +5,38 @@ use crate::args::Args;
 use crate::config_json::{
     first_time_generate_api_key, read_config_json, write_config_json, GenerateApiKeyResult,
 };
+use crate::report::Report;
+
+pub fn generate_api_key(args: &Args) -> Result<Report> {
+    let mut report = Report::new("generate-api-key");
 
-pub fn generate_api_key(args: &Args) -> Result<()> {
     let mut config_json = read_config_json(&args.config_json_path)?;
 
-    match first_time_generate_api_key(&mut config_json)? {
-        GenerateApiKeyResult::UnconfiguredDevice => info!("Unconfigured device"),
+    let result = first_time_generate_api_key(&mut config_json)?;
+
+    report.generate_api_key = Some(result);
+
+    match result {
+        GenerateApiKeyResult::UnconfiguredDevice => {
+            info!("Unconfigured device");
+            report.unconfigured = true;
+        }
         GenerateApiKeyResult::GeneratedAlready => info!("`deviceApiKey` already generated"),
         GenerateApiKeyResult::Reusing => {
             info!("Reusing stored `deviceApiKey`");
             write_config_json(&args.config_json_path, &config_json)?;
+            report
+                .written_files
+                .push(args.config_json_path.to_string_lossy().into());
         }
         GenerateApiKeyResult::GeneratedNew => {
             info!("New `deviceApiKey` generated");
             write_config_json(&args.config_json_path, &config_json)?;
+            report
+                .written_files
+                .push(args.config_json_path.to_string_lossy().into());
         }
     }
 
-    Ok(())
+    Ok(report)
 }
@@ -0,0 +1,287 @@
+// Generation module
+//
+// Keeps the last applied configurations (config.json plus the contents of
+// every schema managed file) under the state directory, so that a bad
+// configuration pushed from the cloud can be rolled back with
+// `os-config rollback`. Generations are numbered and stored as
+// `<state dir>/generations/<number>.json`, with `current` holding the number
+// of the generation that is applied on the device.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config_json::{read_config_json, ConfigMap};
+use crate::fs;
+use crate::join::{get_config_contents, ServiceFiles};
+use crate::remote::{FileContents, RemoteConfiguration};
+use crate::schema::OsConfigSchema;
+
+const GENERATIONS_DIR: &str = "generations";
+const CURRENT_FILE: &str = "current";
+const GENERATIONS_TO_KEEP: usize = 5;
+
+// Contents of a file of a generation, `null` for a file that did not exist. Unlike in the
+// remote configuration a file may be absent here, so that a rollback deletes it.
+pub type FileState = Option<FileContents>;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Generation {
+    pub config_json: ConfigMap,
+    pub services: HashMap<String, HashMap<String, FileState>>,
+}
+
+impl Generation {
+    pub fn new(
+        config_json: &ConfigMap,
+        schema: &OsConfigSchema,
+        remote_config: &RemoteConfiguration,
+    ) -> Result<Self> {
+        let mut services = HashMap::new();
+
+        for service in &schema.services {
+            let mut files = HashMap::new();
+            for name in service.files.keys() {
+                let contents = remote_config.get_config_contents(&service.id, name)?;
+                files.insert(name.clone(), Some(FileContents::from_bytes(contents)));
+            }
+            services.insert(service.id.clone(), files);
+        }
+
+        Ok(Generation {
+            config_json: config_json.clone(),
+            services,
+        })
+    }
+
+    // The state of the device as it is on disk
+    pub fn from_disk(config_json_path: &Path, schema: &OsConfigSchema) -> Result<Self> {
+        let config_json = read_config_json(config_json_path)?;
+
+        let mut services = HashMap::new();
+
+        for service in &schema.services {
+            let mut files = HashMap::new();
+            for (name, config_file) in &service.files {
+                let state = if Path::new(&config_file.path).exists() {
+                    Some(FileContents::from_bytes(get_config_contents(
+                        &config_file.path,
+                    )))
+                } else {
+                    None
+                };
+                files.insert(name.clone(), state);
+            }
+            services.insert(service.id.clone(), files);
+        }
+
+        Ok(Generation {
+            config_json,
+            services,
+        })
+    }
+
+    // A generation with absent files has no remote configuration equivalent
+    pub fn to_remote_config(&self) -> Result<Option<RemoteConfiguration>> {
+        let mut services = HashMap::new();
+
+        for (service_id, files) in &self.services {
+            let mut contents = HashMap::new();
+            for (name, state) in files {
+                let Some(state) = state else {
+                    return Ok(None);
+                };
+                contents.insert(name.clone(), state.clone());
+            }
+            services.insert(service_id.clone(), contents);
+        }
+
+        Ok(Some(serde_json::from_value(json!({
+            "services": services,
+            "config": {
+                "overrides": {}
+            }
+        }))?))
+    }
+}
+
+impl ServiceFiles for Generation {
+    fn file_contents(&self, service_id: &str, config_name: &str) -> Result<Option<Vec<u8>>> {
+        let state = self
+            .services
+            .get(service_id)
+            .and_then(|files| files.get(config_name))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Service `{}` config `{}` not found in the generation",
+                    service_id,
+                    config_name
+                )
+            })?;
+
+        state.as_ref().map(FileContents::to_bytes).transpose()
+    }
+}
+
+pub fn list_generations(state_dir: &Path) -> Result<Vec<u64>> {
+    let dir = generations_dir(state_dir);
+
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut generations = Vec::new();
+
+    for entry in ::std::fs::read_dir(&dir).context(format!("Reading {dir:?} failed"))? {
+        let path = entry?.path();
+
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+
+        if let Some(Ok(number)) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(str::parse)
+        {
+            generations.push(number);
+        }
+    }
+
+    generations.sort();
+
+    Ok(generations)
+}
+
+pub fn current_generation(state_dir: &Path) -> Result<Option<u64>> {
+    let path = generations_dir(state_dir).join(CURRENT_FILE);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_file(&path)?;
+
+    Ok(Some(
+        contents
+            .trim()
+            .parse()
+            .context(format!("Parsing {path:?} failed"))?,
+    ))
+}
+
+pub fn set_current_generation(state_dir: &Path, number: u64) -> Result<()> {
+    fs::write_file(
+        &generations_dir(state_dir).join(CURRENT_FILE),
+        &format!("{number}\n"),
+        None,
+    )
+}
+
+pub fn read_generation(state_dir: &Path, number: u64) -> Result<Generation> {
+    let path = generation_path(state_dir, number);
+
+    let contents = fs::read_file(&path)?;
+
+    serde_json::from_str(&contents).context(format!("Parsing {path:?} failed"))
+}
+
+// Stores the generation as the current one and prunes the oldest generations
+pub fn record_generation(state_dir: &Path, generation: &Generation) -> Result<u64> {
+    let dir = generations_dir(state_dir);
+
+    ::std::fs::create_dir_all(&dir).context(format!("Creating {dir:?} failed"))?;
+
+    let generations = list_generations(state_dir)?;
+
+    let number = generations.last().map_or(1, |last| last + 1);
+
+    // Generations hold config.json secrets, so keep them private
+    fs::write_file(
+        &generation_path(state_dir, number),
+        &serde_json::to_string_pretty(generation)?,
+        Some(0o600),
+    )?;
+
+    set_current_generation(state_dir, number)?;
+
+    let prune_count = (generations.len() + 1).saturating_sub(GENERATIONS_TO_KEEP);
+    for old in &generations[..prune_count] {
+        fs::remove_file(&generation_path(state_dir, *old))?;
+    }
+
+    debug!("Recorded configuration generation {}", number);
+
+    Ok(number)
+}
+
+fn generations_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join(GENERATIONS_DIR)
+}
+
+fn generation_path(state_dir: &Path, number: u64) -> PathBuf {
+    generations_dir(state_dir).join(format!("{number}.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn generation(hostname: &str) -> Generation {
+        Generation {
+            config_json: json!({ "hostname": hostname }).as_object().unwrap().clone(),
+            services: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn record_and_read_generations() {
+        let tmp_dir = TempDir::new().unwrap();
+        let state_dir = tmp_dir.path();
+
+        assert!(list_generations(state_dir).unwrap().is_empty());
+        assert_eq!(current_generation(state_dir).unwrap(), None);
+
+        assert_eq!(record_generation(state_dir, &generation("one")).unwrap(), 1);
+        assert_eq!(record_generation(state_dir, &generation("two")).unwrap(), 2);
+
+        assert_eq!(list_generations(state_dir).unwrap(), vec![1, 2]);
+        assert_eq!(current_generation(state_dir).unwrap(), Some(2));
+        assert_eq!(read_generation(state_dir, 1).unwrap(), generation("one"));
+    }
+
+    #[test]
+    fn absent_files() {
+        let mut generation = generation("one");
+        generation.services = hashmap! {
+            "mock-1".into() => hashmap! {
+                "present".into() => Some("contents".into()),
+                "absent".into() => None,
+            }
+        };
+
+        assert_eq!(
+            generation.file_contents("mock-1", "present").unwrap(),
+            Some(b"contents".to_vec())
+        );
+        assert_eq!(generation.file_contents("mock-1", "absent").unwrap(), None);
+        assert_eq!(generation.to_remote_config().unwrap(), None);
+    }
+
+    #[test]
+    fn prune_old_generations() {
+        let tmp_dir = TempDir::new().unwrap();
+        let state_dir = tmp_dir.path();
+
+        for i in 0..GENERATIONS_TO_KEEP + 2 {
+            record_generation(state_dir, &generation(&format!("host-{i}"))).unwrap();
+        }
+
+        assert_eq!(list_generations(state_dir).unwrap(), vec![3, 4, 5, 6, 7]);
+        assert_eq!(current_generation(state_dir).unwrap(), Some(7));
+    }
+}
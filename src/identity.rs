@@ -0,0 +1,214 @@
+// Identity module
+//
+// Loads the client certificate os-config authenticates with when the API
+// requires mutual TLS. The certificate and its key are either embedded in
+// config.json as base64 encoded PEM, like `balenaRootCA`, or stored as PEM
+// files on the boot partition.
+
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config_json::ConfigMap;
+use crate::fs::read_file_bytes;
+
+const CLIENT_CERT_KEY: &str = "balenaClientCert";
+const CLIENT_KEY_KEY: &str = "balenaClientKey";
+const CLIENT_CERT_FILE: &str = "balena-client.crt";
+const CLIENT_KEY_FILE: &str = "balena-client.key";
+
+// A client certificate chain and the key of its leaf, in the form either TLS backend takes
+#[derive(Clone)]
+pub struct ClientIdentity {
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl ClientIdentity {
+    pub fn to_reqwest(&self) -> Result<reqwest::Identity> {
+        let mut chain = Vec::new();
+        for certificate in &self.chain {
+            chain.extend(certificate.to_pem()?);
+        }
+
+        // The native TLS backend only takes PKCS #8 keys
+        let key = self.key.private_key_to_pem_pkcs8()?;
+
+        Ok(reqwest::Identity::from_pkcs8_pem(&chain, &key)?)
+    }
+
+    pub fn to_rustls(&self) -> Result<(Vec<rustls::Certificate>, rustls::PrivateKey)> {
+        let mut chain = Vec::new();
+        for certificate in &self.chain {
+            chain.push(rustls::Certificate(certificate.to_der()?));
+        }
+
+        let key = rustls::PrivateKey(self.key.private_key_to_pkcs8()?);
+
+        Ok((chain, key))
+    }
+}
+
+pub fn get_client_identity(
+    config_json: &ConfigMap,
+    boot_dir: &Path,
+) -> Result<Option<ClientIdentity>> {
+    get_client_identity_impl(config_json, boot_dir).context("Loading client certificate failed")
+}
+
+fn get_client_identity_impl(
+    config_json: &ConfigMap,
+    boot_dir: &Path,
+) -> Result<Option<ClientIdentity>> {
+    let (cert, key) = if let Some(pems) = read_embedded_pems(config_json)? {
+        pems
+    } else if let Some(pems) = read_pem_files(boot_dir)? {
+        pems
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(parse_identity(&cert, &key)?))
+}
+
+fn read_embedded_pems(config_json: &ConfigMap) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match (
+        get_base64_pem(config_json, CLIENT_CERT_KEY)?,
+        get_base64_pem(config_json, CLIENT_KEY_KEY)?,
+    ) {
+        (Some(cert), Some(key)) => Ok(Some((cert, key))),
+        (None, None) => Ok(None),
+        (Some(_), None) => bail!("`{}` is set without `{}`", CLIENT_CERT_KEY, CLIENT_KEY_KEY),
+        (None, Some(_)) => bail!("`{}` is set without `{}`", CLIENT_KEY_KEY, CLIENT_CERT_KEY),
+    }
+}
+
+fn read_pem_files(boot_dir: &Path) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let cert_path = boot_dir.join(CLIENT_CERT_FILE);
+    let key_path = boot_dir.join(CLIENT_KEY_FILE);
+
+    match (cert_path.exists(), key_path.exists()) {
+        (true, true) => Ok(Some((
+            read_file_bytes(&cert_path)?,
+            read_file_bytes(&key_path)?,
+        ))),
+        (false, false) => Ok(None),
+        (true, false) => bail!("{:?} found without {:?}", cert_path, key_path),
+        (false, true) => bail!("{:?} found without {:?}", key_path, cert_path),
+    }
+}
+
+fn get_base64_pem(config_json: &ConfigMap, key: &str) -> Result<Option<Vec<u8>>> {
+    if let Some(value) = config_json.get(key) {
+        if let Some(pem) = value.as_str() {
+            Ok(Some(
+                STANDARD
+                    .decode(pem)
+                    .context(format!("`{key}` base64 decoding failed"))?,
+            ))
+        } else {
+            bail!("`{}` should be a string", key)
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+// Checking the material here gives a clearer error than a failed TLS handshake
+fn parse_identity(cert: &[u8], key: &[u8]) -> Result<ClientIdentity> {
+    let chain = X509::stack_from_pem(cert).context("Not a valid PEM encoded client certificate")?;
+
+    let Some(leaf) = chain.first() else {
+        bail!("No client certificate found");
+    };
+
+    let key = PKey::private_key_from_pem(key).context("Not a valid PEM encoded client key")?;
+
+    if !leaf.public_key()?.public_eq(&key) {
+        bail!("Client key does not match the client certificate");
+    }
+
+    let identity = ClientIdentity { chain, key };
+
+    // Fail here rather than on the first request
+    identity.to_reqwest()?;
+
+    Ok(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    fn embedded(cert: &str, key: &str) -> ConfigMap {
+        let mut config_json = ConfigMap::new();
+        config_json.insert(
+            CLIENT_CERT_KEY.into(),
+            Value::String(test_utils::cert_for_json(cert)),
+        );
+        config_json.insert(
+            CLIENT_KEY_KEY.into(),
+            Value::String(test_utils::cert_for_json(key)),
+        );
+        config_json
+    }
+
+    #[test]
+    fn no_client_identity() {
+        let tmp_dir = TempDir::new().unwrap();
+        assert!(get_client_identity(&ConfigMap::new(), tmp_dir.path())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn embedded_client_identity() {
+        let (key, cert) = test_utils::generate_self_signed_cert();
+
+        let identity = get_client_identity(&embedded(&cert, &key), Path::new("/nonexistent"));
+
+        assert!(identity.unwrap().is_some());
+    }
+
+    #[test]
+    fn client_identity_files() {
+        let (key, cert) = test_utils::generate_self_signed_cert();
+
+        let tmp_dir = TempDir::new().unwrap();
+        test_utils::create_tmp_file(&tmp_dir, CLIENT_CERT_FILE, &cert, None);
+        test_utils::create_tmp_file(&tmp_dir, CLIENT_KEY_FILE, &key, None);
+
+        let identity = get_client_identity(&ConfigMap::new(), tmp_dir.path());
+
+        assert!(identity.unwrap().is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "`balenaClientCert` is set without `balenaClientKey`")]
+    fn client_cert_without_key() {
+        let (_key, cert) = test_utils::generate_self_signed_cert();
+
+        let mut config_json = embedded(&cert, "");
+        config_json.remove(CLIENT_KEY_KEY);
+
+        get_client_identity(&config_json, Path::new("/nonexistent")).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Client key does not match the client certificate")]
+    fn client_key_mismatch() {
+        let (_key, cert) = test_utils::generate_self_signed_cert();
+        let (other_key, _other_cert) = test_utils::generate_self_signed_cert();
+
+        get_client_identity(&embedded(&cert, &other_key), Path::new("/nonexistent")).unwrap();
+    }
+}
@@ -1,83 +1,430 @@
 use crate::fs;
+use std::io::{self, Read};
 use std::path::Path;
 
-use crate::args::{Args, SUPERVISOR_SERVICE};
+use crate::ack::applied_state;
+use crate::args::{Args, JsonConfigSource, SUPERVISOR_SERVICE};
+use crate::cache::{
+    clear_validators, read_configuration, read_validators, write_configuration, write_validators,
+};
 use crate::config_json::{
-    get_api_endpoint, get_root_certificate, merge_config_json, read_config_json, write_config_json,
-    ConfigMap,
+    get_api_endpoint, get_api_mirrors, get_root_certificate, get_signing_key, merge_config_json,
+    read_config_json, write_config_json, ConfigMap,
 };
+use crate::diff::{changed_keys, config_json_diff, unified_diff};
+use crate::exit::Failure;
+use crate::generation::{list_generations, record_generation, Generation};
+use crate::identity::get_client_identity;
 use crate::migrate::migrate_config_json;
-use crate::remote::{config_url, fetch_configuration, RemoteConfiguration};
-use crate::schema::{read_os_config_schema, OsConfigSchema};
+use crate::pin::get_api_pins;
+use crate::proxy::get_proxy;
+use crate::remote::{
+    fetch_configuration, is_unreachable, read_local_configuration, send_state, Endpoint,
+    FetchOptions, Fetched, Payload, RemoteConfiguration, Validators,
+};
+use crate::report::Report;
+use crate::retry::RetryPolicy;
+use crate::schema::{read_os_config_schema, sorted_files, OsConfigSchema};
+use crate::signal;
+use crate::signature::{parse_signing_key, SigningKey};
 use crate::systemd;
-use anyhow::Result;
+use crate::transaction::Transaction;
+use anyhow::{anyhow, Context, Result};
 
-pub fn join(args: &Args) -> Result<()> {
+pub fn join(args: &Args) -> Result<Report> {
     let mut config_json = read_config_json(&args.config_json_path)?;
 
     let schema = read_os_config_schema(&args.os_config_path)?;
 
-    if let Some(ref json_config) = args.json_config {
+    let previous_api_endpoint = get_api_endpoint(&config_json)?;
+
+    if let Some(ref source) = args.json_config {
+        let json_config = read_json_config(source)?;
+
         clean_config_json_keys(&mut config_json, &schema);
 
-        merge_config_json(&mut config_json, json_config)?;
+        merge_config_json(&mut config_json, &json_config)?;
     } else {
         unreachable!()
     };
 
-    reconfigure(args, &mut config_json, true)
+    // Validators issued by the previous endpoint mean nothing to the new one
+    if !args.dry_run && get_api_endpoint(&config_json)? != previous_api_endpoint {
+        if let Err(err) = clear_validators(&args.state_dir) {
+            warn!("Clearing cached validators failed: {:#}", err);
+        }
+    }
+
+    reconfigure(args, &schema, &mut config_json, true)
 }
 
-pub fn reconfigure(args: &Args, config_json: &mut ConfigMap, joining: bool) -> Result<()> {
-    let schema = read_os_config_schema(&args.os_config_path)?;
+fn read_json_config(source: &JsonConfigSource) -> Result<String> {
+    match source {
+        JsonConfigSource::Argument(contents) => Ok(contents.clone()),
+        JsonConfigSource::File(path) => fs::read_file(path),
+        JsonConfigSource::Stdin => {
+            let mut contents = String::new();
+            io::stdin()
+                .read_to_string(&mut contents)
+                .context("Reading provisioning JSON from stdin failed")?;
+            Ok(contents)
+        }
+    }
+}
 
-    let api_endpoint = if let Some(api_endpoint) = get_api_endpoint(config_json)? {
-        api_endpoint
-    } else {
+pub fn reconfigure(
+    args: &Args,
+    schema: &OsConfigSchema,
+    config_json: &mut ConfigMap,
+    joining: bool,
+) -> Result<Report> {
+    let mut report = Report::new(if joining { "join" } else { "update" });
+    report.dry_run = args.dry_run;
+
+    let api_endpoint = get_api_endpoint(config_json)?;
+
+    // A local source does not need the API, e.g. on an air-gapped device provisioned from a bundle
+    if api_endpoint.is_none() && args.source.is_none() {
         info!("Unconfigured device. Exiting...");
-        return Ok(());
-    };
+        report.unconfigured = true;
+        return Ok(report);
+    }
 
-    let root_certificate = get_root_certificate(config_json)?;
+    let signing_key = signing_key(config_json, schema)?;
+
+    let (remote_config, source) = if args.offline {
+        info!("Using cached service configuration");
+        report.cached = true;
+
+        let remote_config =
+            read_configuration(&args.state_dir, signing_key.as_ref()).ok_or_else(|| {
+                anyhow!(Failure::Fetch(
+                    "No cached service configuration found".into()
+                ))
+            })?;
+
+        (remote_config, Source::Cache)
+    } else {
+        let fetched = if let Some(ref source) = args.source {
+            read_local_configuration(source, signing_key.as_ref()).map(
+                |(remote_config, payload)| {
+                    Fetched::Modified(Box::new(remote_config), payload, None)
+                },
+            )
+        } else if let Some(api_endpoint) = api_endpoint {
+            // Joining always applies the configuration, so it never asks for a conditional response
+            let validators = if joining {
+                None
+            } else {
+                read_validators(&args.state_dir)
+            };
+
+            let endpoints = endpoints(config_json, api_endpoint)?;
+
+            let options = fetch_options(args, schema, config_json, validators)?;
+
+            fetch_configuration(
+                &endpoints,
+                &args.config_route,
+                &args.config_json_path,
+                options,
+            )
+        } else {
+            unreachable!()
+        };
+
+        match fetched {
+            Ok(Fetched::Modified(remote_config, payload, validators)) => {
+                (*remote_config, Source::Remote(payload, validators))
+            }
+            // The files may still have drifted from the unchanged configuration
+            Ok(Fetched::NotModified) => {
+                match read_configuration(&args.state_dir, signing_key.as_ref()) {
+                    Some(remote_config) => (remote_config, Source::Cache),
+                    None => {
+                        info!("No configuration changes");
+                        return Ok(report);
+                    }
+                }
+            }
+            // Reapplying the last configuration gets a device with wiped files back without
+            // connectivity, the fetch is retried on the next update anyway. A refusing API or a
+            // payload failing verification is never papered over.
+            Err(err) if !joining && is_unreachable(&err) => {
+                match read_configuration(&args.state_dir, signing_key.as_ref()) {
+                    Some(remote_config) => {
+                        warn!("{:#}", err);
+                        info!("Falling back to cached service configuration");
+                        report.cached = true;
+
+                        (remote_config, Source::Cache)
+                    }
+                    None => return Err(err),
+                }
+            }
+            Err(err) => return Err(err),
+        }
+    };
 
-    let remote_config = fetch_configuration(
-        &config_url(&api_endpoint, &args.config_route),
-        root_certificate,
-        !joining,
-    )?;
+    let has_service_config_changes = has_service_config_changes(schema, &remote_config)?;
 
-    let has_service_config_changes = has_service_config_changes(&schema, &remote_config)?;
+    let unmigrated_config_json = config_json.clone();
 
     let has_config_json_migrations =
-        migrate_config_json(&schema, &remote_config.config, config_json);
+        migrate_config_json(schema, &remote_config.config, config_json);
+
+    report.service_config_changes = has_service_config_changes;
+    report.migrated_keys = changed_keys(&unmigrated_config_json, config_json);
+
+    if args.diff {
+        print_diff(args, schema, &remote_config, config_json)?;
+    }
 
     if !has_service_config_changes && !has_config_json_migrations {
         info!("No configuration changes");
 
         if !joining {
-            return Ok(());
+            if !args.dry_run {
+                store_cache(args, source);
+            }
+
+            return Ok(report);
         }
     }
 
+    let should_write_config_json = joining || has_config_json_migrations;
+
+    if args.dry_run {
+        print_plan(
+            args,
+            schema,
+            &remote_config,
+            has_service_config_changes,
+            should_write_config_json,
+            &mut report,
+        )?;
+
+        return Ok(report);
+    }
+
+    // The fetch may have taken long enough for the daemon to be asked to stop meanwhile
+    if signal::should_terminate() {
+        info!("Terminating, configuration not applied");
+        return Ok(report);
+    }
+
+    // Keep the configuration from before the first apply, so that it can be rolled back to
+    if matches!(list_generations(&args.state_dir), Ok(generations) if generations.is_empty()) {
+        record(args, Generation::from_disk(&args.config_json_path, schema));
+    }
+
+    if let Err(err) = apply(
+        args,
+        config_json,
+        schema,
+        &remote_config,
+        has_service_config_changes,
+        should_write_config_json,
+        &mut report,
+    ) {
+        report_state(args, schema, config_json, &report, Some(&err));
+
+        return Err(err);
+    }
+
+    report.generation = record(args, Generation::new(config_json, schema, &remote_config));
+
+    store_cache(args, source);
+
+    report_state(args, schema, config_json, &report, None);
+
+    Ok(report)
+}
+
+// Where the configuration being applied came from
+enum Source {
+    Remote(Payload, Option<Validators>),
+    Cache,
+}
+
+// Only an applied configuration and its validators are cached, so that a 304 always
+// means that the device is up to date
+fn store_cache(args: &Args, source: Source) {
+    let (payload, validators) = match source {
+        Source::Remote(payload, validators) => (payload, validators),
+        Source::Cache => return,
+    };
+
+    let result = write_configuration(&args.state_dir, &payload).and_then(|_| match validators {
+        Some(ref validators) => write_validators(&args.state_dir, validators),
+        None => clear_validators(&args.state_dir),
+    });
+
+    if let Err(err) = result {
+        warn!("Caching service configuration failed: {:#}", err);
+    }
+}
+
+fn fetch_options(
+    args: &Args,
+    schema: &OsConfigSchema,
+    config_json: &ConfigMap,
+    validators: Option<Validators>,
+) -> Result<FetchOptions> {
+    Ok(FetchOptions {
+        identity: get_client_identity(config_json, &args.boot_dir)?,
+        proxy: get_proxy(args.proxy.as_deref(), &args.boot_dir)?,
+        retry_policy: retry_policy(args, schema),
+        validators,
+        signing_key: signing_key(config_json, schema)?,
+    })
+}
+
+// Acknowledges what got applied when a state route is set, failing to do so never fails
+// the apply itself
+fn report_state(
+    args: &Args,
+    schema: &OsConfigSchema,
+    config_json: &ConfigMap,
+    report: &Report,
+    error: Option<&anyhow::Error>,
+) {
+    let Some(ref state_route) = args.state_route else {
+        return;
+    };
+
+    if let Err(err) = send_applied_state(args, schema, config_json, state_route, report, error) {
+        warn!("Reporting configuration state failed: {:#}", err);
+    }
+}
+
+fn send_applied_state(
+    args: &Args,
+    schema: &OsConfigSchema,
+    config_json: &ConfigMap,
+    state_route: &str,
+    report: &Report,
+    error: Option<&anyhow::Error>,
+) -> Result<()> {
+    // Devices provisioned from a local source may have no API to report to
+    let Some(api_endpoint) = get_api_endpoint(config_json)? else {
+        return Ok(());
+    };
+
+    let endpoint = primary_endpoint(config_json, api_endpoint)?;
+    let options = fetch_options(args, schema, config_json, None)?;
+
+    let state = applied_state(schema, &report.migrated_keys, report.generation, error);
+
+    send_state(
+        &endpoint,
+        state_route,
+        &args.config_json_path,
+        &options,
+        &state,
+    )
+}
+
+fn primary_endpoint(config_json: &ConfigMap, api_endpoint: String) -> Result<Endpoint> {
+    Ok(Endpoint {
+        api_endpoint,
+        root_certificate: get_root_certificate(config_json)?,
+        pins: get_api_pins(config_json)?,
+    })
+}
+
+// Mirrors without a CA of their own share the CA of `apiEndpoint`
+fn endpoints(config_json: &ConfigMap, api_endpoint: String) -> Result<Vec<Endpoint>> {
+    let primary = primary_endpoint(config_json, api_endpoint)?;
+    let root_certificate = primary.root_certificate.clone();
+    let pinned = !primary.pins.is_empty();
+
+    let mut endpoints = vec![primary];
+
+    for mirror in get_api_mirrors(config_json)? {
+        // An unreachable pinned endpoint must not hand the API key to an unpinned mirror
+        if pinned && mirror.pins.is_empty() {
+            warn!(
+                "Skipping mirror {} without `balenaApiPins` of its own, `apiEndpoint` is pinned",
+                mirror.api_endpoint
+            );
+            continue;
+        }
+
+        endpoints.push(Endpoint {
+            api_endpoint: mirror.api_endpoint,
+            root_certificate: mirror.root_certificate.or_else(|| root_certificate.clone()),
+            pins: mirror.pins,
+        });
+    }
+
+    Ok(endpoints)
+}
+
+// A key pinned in config.json takes precedence over the one of the schema
+fn signing_key(config_json: &ConfigMap, schema: &OsConfigSchema) -> Result<Option<SigningKey>> {
+    if let Some(signing_key) = get_signing_key(config_json)? {
+        return Ok(Some(signing_key));
+    }
+
+    match schema.signing_key {
+        Some(ref pem) => Ok(Some(parse_signing_key(pem.as_bytes()).context(
+            Failure::Schema("Parsing schema `signing_key` failed".into()),
+        )?)),
+        None => Ok(None),
+    }
+}
+
+fn retry_policy(args: &Args, schema: &OsConfigSchema) -> RetryPolicy {
+    let mut policy = RetryPolicy::for_subcommand(&args.subcommand);
+
+    if let Some(ref settings) = schema.retry {
+        policy = policy.with_settings(settings);
+    }
+
+    policy.with_settings(&args.retry)
+}
+
+// Failing to keep a generation should not fail an otherwise successful apply
+fn record(args: &Args, generation: Result<Generation>) -> Option<u64> {
+    match generation.and_then(|generation| record_generation(&args.state_dir, &generation)) {
+        Ok(number) => Some(number),
+        Err(err) => {
+            warn!("Recording configuration generation failed: {:#}", err);
+            None
+        }
+    }
+}
+
+pub fn apply(
+    args: &Args,
+    config_json: &ConfigMap,
+    schema: &OsConfigSchema,
+    files: &impl ServiceFiles,
+    has_service_config_changes: bool,
+    should_write_config_json: bool,
+    report: &mut Report,
+) -> Result<()> {
     if args.supervisor_exists {
         systemd::stop_service(SUPERVISOR_SERVICE)?;
 
         systemd::await_service_exit(SUPERVISOR_SERVICE)?;
     }
 
-    let should_write_config_json = joining || has_config_json_migrations;
-
     let result = reconfigure_core(
         args,
         config_json,
-        &schema,
-        &remote_config,
+        schema,
+        files,
         has_service_config_changes,
         should_write_config_json,
+        report,
     );
 
     if args.supervisor_exists {
         systemd::start_service(SUPERVISOR_SERVICE)?;
+
+        report.restarted_units.push(SUPERVISOR_SERVICE.into());
     }
 
     result
@@ -87,31 +434,177 @@ fn reconfigure_core(
     args: &Args,
     config_json: &ConfigMap,
     schema: &OsConfigSchema,
+    files: &impl ServiceFiles,
+    has_service_config_changes: bool,
+    should_write_config_json: bool,
+    report: &mut Report,
+) -> Result<()> {
+    let mut transaction = Transaction::begin(&touched_paths(
+        args,
+        schema,
+        has_service_config_changes,
+        should_write_config_json,
+    ))?;
+
+    let result = (|| -> Result<()> {
+        if should_write_config_json {
+            write_config_json(&args.config_json_path, config_json)?;
+
+            report
+                .written_files
+                .push(args.config_json_path.to_string_lossy().into());
+        }
+
+        if has_service_config_changes {
+            configure_services(schema, files, &mut transaction, report)?;
+        }
+
+        Ok(())
+    })();
+
+    // Put back the files as they were before the apply if any step failed
+    result.map_err(|err| match transaction.rollback() {
+        Ok(()) => err.context("Configuration changes were rolled back"),
+        Err(rollback_err) => err.context(format!(
+            "Rolling back configuration changes failed: {rollback_err}"
+        )),
+    })
+}
+
+fn touched_paths<'a>(
+    args: &'a Args,
+    schema: &'a OsConfigSchema,
+    has_service_config_changes: bool,
+    should_write_config_json: bool,
+) -> Vec<&'a Path> {
+    let mut paths = Vec::new();
+
+    if should_write_config_json {
+        paths.push(args.config_json_path.as_path());
+    }
+
+    if has_service_config_changes {
+        for service in &schema.services {
+            for (_, config_file) in sorted_files(service) {
+                paths.push(Path::new(&config_file.path));
+            }
+        }
+    }
+
+    paths
+}
+
+fn print_plan(
+    args: &Args,
+    schema: &OsConfigSchema,
     remote_config: &RemoteConfiguration,
     has_service_config_changes: bool,
     should_write_config_json: bool,
+    report: &mut Report,
 ) -> Result<()> {
+    info!("Dry run, the following changes would be applied:");
+
+    // The supervisor is not looked up on a dry run, so it is only stopped if it is there
+    info!("Would stop {} if present", SUPERVISOR_SERVICE);
+
     if should_write_config_json {
-        write_config_json(&args.config_json_path, config_json)?;
+        info!("Would write {}", args.config_json_path.to_string_lossy());
+
+        report
+            .written_files
+            .push(args.config_json_path.to_string_lossy().into());
     }
 
     if has_service_config_changes {
-        configure_services(schema, remote_config)?;
+        for service in &schema.services {
+            for systemd_service in &service.systemd_services {
+                info!("Would stop {}", systemd_service);
+            }
+
+            for (name, config_file) in sorted_files(service) {
+                let future = remote_config.get_config_contents(&service.id, name)?;
+                let current = get_config_contents(&config_file.path);
+
+                if future != current {
+                    info!("Would update {}", &config_file.path);
+
+                    report.written_files.push(config_file.path.clone());
+                }
+            }
+
+            for systemd_service in &service.systemd_services {
+                info!("Would start {}", systemd_service);
+
+                report.restarted_units.push(systemd_service.clone());
+            }
+        }
     }
 
+    info!("Would start {} if present", SUPERVISOR_SERVICE);
+
     Ok(())
 }
 
-fn has_service_config_changes(
+fn print_diff(
+    args: &Args,
     schema: &OsConfigSchema,
     remote_config: &RemoteConfiguration,
-) -> Result<bool> {
+    config_json: &ConfigMap,
+) -> Result<()> {
     for service in &schema.services {
-        for (name, config_file) in &service.files {
+        for (name, config_file) in sorted_files(service) {
             let future = remote_config.get_config_contents(&service.id, name)?;
             let current = get_config_contents(&config_file.path);
 
-            if future != current {
+            // Binary files only get a summary, a line diff of them means nothing
+            match (std::str::from_utf8(&current), std::str::from_utf8(&future)) {
+                (Ok(current), Ok(future)) => {
+                    for line in unified_diff(current, future, &config_file.path) {
+                        info!("{}", line);
+                    }
+                }
+                _ if current != future => info!("Binary file {} differs", &config_file.path),
+                _ => {}
+            }
+        }
+    }
+
+    let current_config_json = read_config_json(&args.config_json_path)?;
+
+    for line in config_json_diff(
+        &current_config_json,
+        config_json,
+        &args.config_json_path.to_string_lossy(),
+    ) {
+        info!("{}", line);
+    }
+
+    Ok(())
+}
+
+// Contents the schema managed files should end up with, `None` for a file that must not exist
+pub trait ServiceFiles {
+    fn file_contents(&self, service_id: &str, config_name: &str) -> Result<Option<Vec<u8>>>;
+}
+
+impl ServiceFiles for RemoteConfiguration {
+    fn file_contents(&self, service_id: &str, config_name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.get_config_contents(service_id, config_name)?))
+    }
+}
+
+pub fn has_service_config_changes(
+    schema: &OsConfigSchema,
+    files: &impl ServiceFiles,
+) -> Result<bool> {
+    for service in &schema.services {
+        for (name, config_file) in &service.files {
+            let changed = match files.file_contents(&service.id, name)? {
+                Some(future) => future != get_config_contents(&config_file.path),
+                None => Path::new(&config_file.path).exists(),
+            };
+
+            if changed {
                 return Ok(true);
             }
         }
@@ -120,9 +613,16 @@ fn has_service_config_changes(
     Ok(false)
 }
 
-fn configure_services(schema: &OsConfigSchema, remote_config: &RemoteConfiguration) -> Result<()> {
+fn configure_services(
+    schema: &OsConfigSchema,
+    files: &impl ServiceFiles,
+    transaction: &mut Transaction,
+    report: &mut Report,
+) -> Result<()> {
     for service in &schema.services {
         for systemd_service in &service.systemd_services {
+            transaction.unit_stopped(systemd_service);
+
             systemd::stop_service(systemd_service)?;
         }
 
@@ -130,31 +630,39 @@ fn configure_services(schema: &OsConfigSchema, remote_config: &RemoteConfigurati
             systemd::await_service_exit(systemd_service)?;
         }
 
-        // Iterate through config files alphanumerically for integration testing consistency
-        let mut names = service.files.keys().collect::<Vec<_>>();
-        names.sort();
-        for name in names {
-            let config_file = &service.files[name as &str];
-            let contents = remote_config.get_config_contents(&service.id, name)?;
-            let mode = fs::parse_mode(&config_file.perm)?;
-            fs::write_file(Path::new(&config_file.path), contents, mode)?;
-            info!("{} updated", &config_file.path);
+        for (name, config_file) in sorted_files(service) {
+            let path = Path::new(&config_file.path);
+
+            match files.file_contents(&service.id, name)? {
+                Some(contents) => {
+                    let mode = fs::parse_mode(&config_file.perm)?;
+                    fs::write_file_bytes(path, &contents, mode)?;
+                    info!("{} updated", &config_file.path);
+
+                    report.written_files.push(config_file.path.clone());
+                }
+                None if path.exists() => {
+                    fs::remove_file(path)?;
+                    info!("{} deleted", &config_file.path);
+
+                    report.deleted_files.push(config_file.path.clone());
+                }
+                None => {}
+            }
         }
 
         for systemd_service in &service.systemd_services {
             systemd::start_service(systemd_service)?;
+
+            report.restarted_units.push(systemd_service.clone());
         }
     }
 
     Ok(())
 }
 
-fn get_config_contents(path: &str) -> String {
-    if let Ok(contents) = fs::read_file(Path::new(path)) {
-        contents
-    } else {
-        "".into()
-    }
+pub fn get_config_contents(path: &str) -> Vec<u8> {
+    fs::read_file_bytes(Path::new(path)).unwrap_or_default()
 }
 
 fn clean_config_json_keys(config_json: &mut ConfigMap, schema: &OsConfigSchema) {
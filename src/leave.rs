@@ -5,16 +5,20 @@ use crate::args::{Args, SUPERVISOR_SERVICE};
 use crate::config_json::{
     get_api_endpoint, read_config_json, store_api_key, write_config_json, ConfigMap,
 };
-use crate::schema::{read_os_config_schema, OsConfigSchema};
+use crate::report::Report;
+use crate::schema::{read_os_config_schema, sorted_files, OsConfigSchema};
 use crate::systemd;
 use anyhow::Result;
 
-pub fn leave(args: &Args) -> Result<()> {
+pub fn leave(args: &Args) -> Result<Report> {
+    let mut report = Report::new("leave");
+
     let mut config_json = read_config_json(&args.config_json_path)?;
 
     if get_api_endpoint(&config_json)?.is_none() {
         info!("Unconfigured device. Exiting...");
-        return Ok(());
+        report.unconfigured = true;
+        return Ok(report);
     };
 
     let schema = read_os_config_schema(&args.os_config_path)?;
@@ -25,40 +29,47 @@ pub fn leave(args: &Args) -> Result<()> {
         systemd::await_service_exit(SUPERVISOR_SERVICE)?;
     }
 
-    let result = deconfigure_core(&mut config_json, args, &schema);
+    let result = deconfigure_core(&mut config_json, args, &schema, &mut report);
 
     if args.supervisor_exists {
         systemd::start_service(SUPERVISOR_SERVICE)?;
+
+        report.restarted_units.push(SUPERVISOR_SERVICE.into());
     }
 
-    result
+    result.map(|_| report)
 }
 
 fn deconfigure_core(
     config_json: &mut ConfigMap,
     args: &Args,
     schema: &OsConfigSchema,
+    report: &mut Report,
 ) -> Result<()> {
     store_api_key(config_json)?;
 
     delete_config_json_keys(config_json, args, schema)?;
 
-    delete_configuration(schema)
+    report
+        .written_files
+        .push(args.config_json_path.to_string_lossy().into());
+
+    delete_configuration(schema, report)
 }
 
-fn delete_configuration(schema: &OsConfigSchema) -> Result<()> {
+fn delete_configuration(schema: &OsConfigSchema, report: &mut Report) -> Result<()> {
     for service in &schema.services {
-        // Iterate through config files alphanumerically for integration testing consistency
-        let mut names = service.files.keys().collect::<Vec<_>>();
-        names.sort();
-        for name in names {
-            let config_file = &service.files[name as &str];
+        for (_, config_file) in sorted_files(service) {
             fs::remove_file(Path::new(&config_file.path))?;
             info!("{} deleted", &config_file.path);
+
+            report.deleted_files.push(config_file.path.clone());
         }
 
         for systemd_service in &service.systemd_services {
             systemd::reload_or_restart_service(systemd_service)?;
+
+            report.restarted_units.push(systemd_service.clone());
         }
     }
 
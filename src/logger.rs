@@ -2,7 +2,9 @@ use env_logger::{Builder, Target};
 use log::LevelFilter;
 use std::env;
 
-pub fn init_logger() {
+use crate::args::OutputFormat;
+
+pub fn init_logger(output: OutputFormat) {
     let mut builder = Builder::new();
 
     if let Ok(log_level) = env::var("OS_CONFIG_LOG_LEVEL") {
@@ -11,8 +13,14 @@ pub fn init_logger() {
         builder.filter(None, LevelFilter::Info);
     }
 
+    // Keep stdout clean for the structured result document
+    let target = match output {
+        OutputFormat::Text => Target::Stdout,
+        OutputFormat::Json => Target::Stderr,
+    };
+
     builder
-        .target(Target::Stdout)
+        .target(target)
         .format_module_path(false)
         .format_level(false)
         .format_target(false)
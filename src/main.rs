@@ -9,6 +9,7 @@ extern crate base64;
 extern crate clap;
 extern crate getrandom;
 extern crate hex;
+extern crate libc;
 extern crate openssl;
 extern crate reqwest;
 
@@ -26,33 +27,88 @@ extern crate maplit;
 
 extern crate fatrw;
 
+mod ack;
 mod args;
+mod cache;
 mod config_json;
+mod daemon;
+mod diff;
+mod exit;
 mod fs;
 mod generate;
+mod generation;
+mod identity;
 mod join;
 mod leave;
 mod logger;
 mod migrate;
+mod notify;
+mod pin;
+mod pointer;
+mod proxy;
 mod random;
 mod remote;
+mod report;
+mod retry;
+mod rollback;
 mod schema;
+mod signal;
+mod signature;
+mod status;
 mod systemd;
+mod transaction;
 mod update;
+mod validate;
+
+use std::process::ExitCode;
 
 use anyhow::Result;
 
-use crate::args::{get_cli_args, OsConfigSubcommand};
+use crate::args::{get_cli_args, Args, OsConfigSubcommand, OutputFormat};
+use crate::exit::ExitStatus;
+use crate::report::Report;
+
+fn main() -> ExitCode {
+    let args = match get_cli_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            return ExitStatus::Failure.into();
+        }
+    };
+
+    logger::init_logger(args.output);
 
-fn main() -> Result<()> {
-    logger::init_logger();
+    match run(&args) {
+        Ok(report) => ExitStatus::from_report(&report, args.detailed_exitcodes).into(),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            if let OutputFormat::Json = args.output {
+                let report = Report::failed(args.subcommand.name(), &err);
+                if let Ok(document) = serde_json::to_string_pretty(&report) {
+                    println!("{document}");
+                }
+            }
+            ExitStatus::from_error(&err).into()
+        }
+    }
+}
 
-    let args = get_cli_args();
+fn run(args: &Args) -> Result<Report> {
+    let report = match args.subcommand {
+        OsConfigSubcommand::GenerateApiKey => generate::generate_api_key(args),
+        OsConfigSubcommand::Update => update::update(args),
+        OsConfigSubcommand::Join => join::join(args),
+        OsConfigSubcommand::Leave => leave::leave(args),
+        OsConfigSubcommand::Status => status::status(args),
+        OsConfigSubcommand::Validate => validate::validate(args),
+        OsConfigSubcommand::Rollback => rollback::rollback(args),
+        OsConfigSubcommand::Daemon => daemon::daemon(args),
+    }?;
 
-    match args.subcommand {
-        OsConfigSubcommand::GenerateApiKey => generate::generate_api_key(&args),
-        OsConfigSubcommand::Update => update::update(&args),
-        OsConfigSubcommand::Join => join::join(&args),
-        OsConfigSubcommand::Leave => leave::leave(&args),
+    if let OutputFormat::Json = args.output {
+        println!("{}", serde_json::to_string_pretty(&report)?);
     }
+
+    Ok(report)
 }
@@ -2,9 +2,16 @@
 //
 // Provides methods for migrating config.json fields based on remote directives
 // from /os/vX/config. Limits migrated fields based on os-config.json schema
-// whitelist.
+// whitelist. Directives name either a top-level key or a JSON pointer to a
+// nested node, e.g. `/os/udevRules/56`. Deletes only remove object members,
+// as the same directives are served on every update and deleting an array
+// element by index would remove the next one each time.
+
+use serde_json::Value;
 
 use crate::config_json::ConfigMap;
+use crate::pin::{parse_pins, API_PINS_KEY};
+use crate::pointer::{get_node, is_whitelisted, parse_pointer, remove_node, set_node};
 use crate::remote::{ConfigMigrationInstructions, OverridesMap};
 use crate::schema::OsConfigSchema;
 
@@ -17,11 +24,20 @@ pub fn migrate_config_json(
 
     let overridden = handle_override_directives(schema, &migration.overrides, config_json);
 
-    if overridden {
+    let deleted = handle_delete_directives(
+        schema,
+        &migration.deletes,
+        &migration.overrides,
+        config_json,
+    );
+
+    let migrated = overridden || deleted;
+
+    if migrated {
         info!("Done config.json migrations");
     }
 
-    overridden
+    migrated
 }
 
 fn handle_override_directives(
@@ -36,35 +52,142 @@ fn handle_override_directives(
     items.sort_by_key(|pair| pair.0);
 
     for (key, new_value) in items {
-        if !schema.config.whitelist.contains(key) {
+        let tokens = parse_pointer(key);
+
+        if !is_whitelisted(&schema.config.whitelist, &tokens) {
             info!("Key `{}` not in whitelist, skipping", key);
             continue;
         }
 
-        if let Some(existing_value) = config_json.get_mut(key) {
-            if new_value != existing_value {
-                info!(
-                    "Key `{}` found with existing value `{}`, will override to `{}`",
-                    key, existing_value, new_value
-                );
-                *existing_value = new_value.clone();
-                overridden = true;
-            } else {
+        // Only the targeted node changes, the rest of the top-level value is kept
+        let Some((top_key, path)) = tokens.split_first() else {
+            continue;
+        };
+        let existing_top = config_json.get(top_key);
+
+        // The same overrides are served on every update, so an append only happens once
+        if let Some((last, parent)) = path.split_last() {
+            if last == "-" {
+                if let Some(Value::Array(items)) =
+                    existing_top.and_then(|existing_top| get_node(existing_top, parent))
+                {
+                    if items.contains(new_value) {
+                        debug!(
+                            "Key `{}` already holds value `{}`, skipping",
+                            key, new_value
+                        );
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let mut new_top = existing_top.cloned().unwrap_or(Value::Null);
+        if let Err(err) = set_node(&mut new_top, path, new_value.clone()) {
+            warn!("Key `{}` override is invalid, skipping: {:#}", key, err);
+            continue;
+        }
+
+        // Malformed pins would cut the device off the API for good
+        if top_key == API_PINS_KEY {
+            if let Err(err) = parse_pins(&new_top) {
+                warn!("Key `{}` override is invalid, skipping: {:#}", key, err);
+                continue;
+            }
+        }
+
+        match existing_top.and_then(|existing_top| get_node(existing_top, path)) {
+            Some(existing_value) if existing_value == new_value => {
                 debug!(
                     "Key `{}` found with existing value `{}` equal to override value `{}`, skipping",
                     key, existing_value, new_value
                 );
+                continue;
             }
-        } else {
-            info!("Key `{}` not found, will insert `{}`", key, new_value);
-            config_json.insert(key.to_string(), new_value.clone());
-            overridden = true;
+            Some(existing_value) => info!(
+                "Key `{}` found with existing value `{}`, will override to `{}`",
+                key, existing_value, new_value
+            ),
+            None => info!("Key `{}` not found, will insert `{}`", key, new_value),
         }
+
+        config_json.insert(top_key.clone(), new_top);
+        overridden = true;
     }
 
     overridden
 }
 
+fn handle_delete_directives(
+    schema: &OsConfigSchema,
+    deletes: &[String],
+    overrides: &OverridesMap,
+    config_json: &mut ConfigMap,
+) -> bool {
+    let mut deleted = false;
+
+    // Sort deletes in order for tests to have predictable order
+    let mut keys = deletes.iter().collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        let tokens = parse_pointer(key);
+
+        if !is_whitelisted(&schema.config.whitelist, &tokens) {
+            info!("Key `{}` not in whitelist, skipping", key);
+            continue;
+        }
+
+        // Contradicting directives leave the overridden value in place
+        if overrides.contains_key(key) {
+            warn!(
+                "Key `{}` is both overridden and deleted, skipping deletion",
+                key
+            );
+            continue;
+        }
+
+        let Some((top_key, path)) = tokens.split_first() else {
+            continue;
+        };
+
+        if let Some((_, parent)) = path.split_last() {
+            let parent = config_json
+                .get(top_key)
+                .and_then(|top| get_node(top, parent));
+
+            if matches!(parent, Some(Value::Array(_))) {
+                warn!(
+                    "Key `{}` is an array element, only object members can be deleted, skipping",
+                    key
+                );
+                continue;
+            }
+        }
+
+        let removed = if path.is_empty() {
+            config_json.remove(top_key)
+        } else {
+            config_json
+                .get_mut(top_key)
+                .and_then(|top| remove_node(top, path))
+        };
+
+        if let Some(existing_value) = removed {
+            info!(
+                "Key `{}` found with existing value `{}`, will delete",
+                key, existing_value
+            );
+            deleted = true;
+        } else {
+            debug!("Key `{}` not found, skipping deletion", key);
+        }
+    }
+
+    deleted
+}
+
 mod tests {
     #[test]
     fn test_generate_config_json_migration() {
@@ -127,4 +250,187 @@ mod tests {
         assert_eq!(config.get("deadca4f").unwrap(), "new_field");
         assert!(config.get("not_on_whitelist1").is_none());
     }
+
+    #[test]
+    fn test_delete_config_json_keys() {
+        let schema = r#"
+            {
+                "services": [
+                ],
+                "keys": [],
+                "config": {
+                    "whitelist": ["logsEndpoint", "deprecatedFlag", "vpnPort"]
+                }
+            }
+            "#;
+
+        let mut config = serde_json::from_str::<super::ConfigMap>(
+            r#"{"logsEndpoint": "https://logs.resin.io", "vpnPort": 443, "hostname": "balena"}"#,
+        )
+        .unwrap();
+
+        let configuration = r#"
+            {
+                "overrides": {
+                    "vpnPort": 1194
+                },
+                "deletes": ["logsEndpoint", "deprecatedFlag", "vpnPort", "hostname"]
+            }
+            "#;
+
+        assert!(super::migrate_config_json(
+            &serde_json::from_str(schema).unwrap(),
+            &serde_json::from_str(configuration).unwrap(),
+            &mut config,
+        ));
+        assert!(config.get("logsEndpoint").is_none());
+        assert_eq!(config.get("vpnPort").unwrap(), 1194);
+        assert_eq!(config.get("hostname").unwrap(), "balena");
+
+        let only_missing = r#"
+            {
+                "overrides": {},
+                "deletes": ["logsEndpoint"]
+            }
+            "#;
+
+        assert!(!super::migrate_config_json(
+            &serde_json::from_str(schema).unwrap(),
+            &serde_json::from_str(only_missing).unwrap(),
+            &mut config,
+        ));
+    }
+
+    #[test]
+    fn test_pointer_overrides() {
+        let schema = r#"
+            {
+                "services": [
+                ],
+                "keys": [],
+                "config": {
+                    "whitelist": ["/os/udevRules", "/os/sshKeys"]
+                }
+            }
+            "#;
+
+        let mut config = serde_json::from_str::<super::ConfigMap>(
+            r#"{"os": {"udevRules": {"10": "old"}, "sshKeys": ["a"], "network": {"wifi": true}}}"#,
+        )
+        .unwrap();
+
+        let configuration = r#"
+            {
+                "overrides": {
+                    "/os/udevRules/56": "new",
+                    "/os/network/wifi": false,
+                    "os": {}
+                },
+                "deletes": ["/os/sshKeys/0", "/os/udevRules/10"]
+            }
+            "#;
+
+        assert!(super::migrate_config_json(
+            &serde_json::from_str(schema).unwrap(),
+            &serde_json::from_str(configuration).unwrap(),
+            &mut config,
+        ));
+        assert_eq!(
+            config["os"],
+            serde_json::json!({
+                "udevRules": {"56": "new"},
+                "sshKeys": ["a"],
+                "network": {"wifi": true}
+            })
+        );
+    }
+
+    #[test]
+    fn test_append_override_once() {
+        let schema = r#"
+            {
+                "services": [
+                ],
+                "keys": [],
+                "config": {
+                    "whitelist": ["/os/sshKeys"]
+                }
+            }
+            "#;
+
+        let mut config =
+            serde_json::from_str::<super::ConfigMap>(r#"{"os": {"sshKeys": ["a"]}}"#).unwrap();
+
+        let configuration = r#"
+            {
+                "overrides": {
+                    "/os/sshKeys/-": "b"
+                }
+            }
+            "#;
+
+        for migrated in [true, false] {
+            assert_eq!(
+                super::migrate_config_json(
+                    &serde_json::from_str(schema).unwrap(),
+                    &serde_json::from_str(configuration).unwrap(),
+                    &mut config,
+                ),
+                migrated
+            );
+        }
+        assert_eq!(config["os"], serde_json::json!({"sshKeys": ["a", "b"]}));
+    }
+
+    #[test]
+    fn test_rotate_api_pins() {
+        let schema = r#"
+            {
+                "services": [
+                ],
+                "keys": [],
+                "config": {
+                    "whitelist": ["balenaApiPins"]
+                }
+            }
+            "#;
+
+        let mut config = serde_json::from_str::<super::ConfigMap>(
+            r#"{"balenaApiPins": ["ShRaAyoGZCg8Xsl1/6JjB1yzFCCCBFC24zEE+Z2F1wk="]}"#,
+        )
+        .unwrap();
+
+        let rotated = r#"
+            {
+                "overrides": {
+                    "balenaApiPins": [
+                        "ShRaAyoGZCg8Xsl1/6JjB1yzFCCCBFC24zEE+Z2F1wk=",
+                        "sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+                    ]
+                }
+            }
+            "#;
+
+        assert!(super::migrate_config_json(
+            &serde_json::from_str(schema).unwrap(),
+            &serde_json::from_str(rotated).unwrap(),
+            &mut config,
+        ));
+        assert_eq!(config["balenaApiPins"].as_array().unwrap().len(), 2);
+
+        let malformed = r#"
+            {
+                "overrides": {
+                    "balenaApiPins": ["not a pin"]
+                }
+            }
+            "#;
+
+        assert!(!super::migrate_config_json(
+            &serde_json::from_str(schema).unwrap(),
+            &serde_json::from_str(malformed).unwrap(),
+            &mut config,
+        ));
+        assert_eq!(config["balenaApiPins"].as_array().unwrap().len(), 2);
+    }
 }
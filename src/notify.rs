@@ -0,0 +1,109 @@
+// Notify module
+//
+// Minimal sd_notify(3) client for readiness and watchdog notifications when
+// running as a `Type=notify` systemd service.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::process;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+
+pub fn notify(state: &str) {
+    let socket_path = if let Ok(socket_path) = env::var(NOTIFY_SOCKET) {
+        socket_path
+    } else {
+        return;
+    };
+
+    if let Err(err) = send_notification(&socket_path, state) {
+        warn!("Notifying systemd with `{}` failed: {:#}", state, err);
+    }
+}
+
+// Half of the watchdog timeout, as recommended by sd_watchdog_enabled(3)
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var(WATCHDOG_USEC).ok()?.parse::<u64>().ok()?;
+
+    if let Ok(pid) = env::var(WATCHDOG_PID) {
+        if pid.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec / 2))
+}
+
+fn send_notification(socket_path: &str, state: &str) -> Result<()> {
+    let path = socket_path.as_bytes();
+
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    if path.is_empty() || path.len() >= address.sun_path.len() {
+        bail!("Invalid {} `{}`", NOTIFY_SOCKET, socket_path);
+    }
+
+    for (dest, src) in address.sun_path.iter_mut().zip(path) {
+        *dest = *src as libc::c_char;
+    }
+
+    // Sockets in the abstract namespace are passed with a leading `@`
+    let address_len = if path[0] == b'@' {
+        address.sun_path[0] = 0;
+        mem::size_of::<libc::sa_family_t>() + path.len()
+    } else {
+        mem::size_of::<libc::sa_family_t>() + path.len() + 1
+    };
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &address as *const libc::sockaddr_un as *const libc::sockaddr,
+            address_len as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixDatagram;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn send_notification_to_socket() {
+        let tmp_dir = TempDir::new().unwrap();
+        let socket_path = tmp_dir.path().join("notify");
+        let receiver = UnixDatagram::bind(&socket_path).unwrap();
+
+        send_notification(socket_path.to_str().unwrap(), "READY=1").unwrap();
+
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
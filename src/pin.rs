@@ -0,0 +1,325 @@
+// Pin module
+//
+// Public key pinning for the API endpoint. `balenaApiPins` in config.json
+// lists base64 encoded SHA-256 hashes of the DER encoded SubjectPublicKeyInfo
+// of the server certificate or of any CA certificate its chain is verified
+// through, optionally prefixed with `sha256/` as
+// printed by `openssl` based pin generators. Pins are checked during the TLS
+// handshake, so that no request, and in particular no API key, ever reaches a
+// server that does not match. Pinned endpoints have to be https ones.
+
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use openssl::sha::sha256;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyParam;
+use openssl::x509::{X509Ref, X509StoreContext, X509};
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ServerName};
+
+use serde_json::Value;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config_json::ConfigMap;
+
+pub const API_PINS_KEY: &str = "balenaApiPins";
+
+const PIN_PREFIX: &str = "sha256/";
+
+pub fn get_api_pins(config_json: &ConfigMap) -> Result<Vec<String>> {
+    match config_json.get(API_PINS_KEY) {
+        Some(value) => parse_pins(value),
+        None => Ok(vec![]),
+    }
+}
+
+// Pins are normalized to plain base64 so that they compare with `spki_pin`
+pub fn parse_pins(value: &Value) -> Result<Vec<String>> {
+    let Some(values) = value.as_array() else {
+        bail!("`{}` should be an array of strings", API_PINS_KEY);
+    };
+
+    let mut pins = Vec::new();
+
+    for value in values {
+        let Some(pin) = value.as_str() else {
+            bail!("`{}` should be an array of strings", API_PINS_KEY);
+        };
+
+        let pin = pin.strip_prefix(PIN_PREFIX).unwrap_or(pin);
+
+        let hash = STANDARD.decode(pin).context(format!(
+            "`{API_PINS_KEY}` pin `{pin}` base64 decoding failed"
+        ))?;
+
+        if hash.len() != 32 {
+            bail!("`{}` pin `{}` is not a SHA-256 hash", API_PINS_KEY, pin);
+        }
+
+        pins.push(pin.to_string());
+    }
+
+    Ok(pins)
+}
+
+pub fn spki_pin(certificate_der: &[u8]) -> Result<String> {
+    let certificate =
+        X509::from_der(certificate_der).context("Parsing server certificate failed")?;
+
+    certificate_pin(&certificate)
+}
+
+fn certificate_pin(certificate: &X509Ref) -> Result<String> {
+    let spki = certificate.public_key()?.public_key_to_der()?;
+
+    Ok(STANDARD.encode(sha256(&spki)))
+}
+
+// Checks the certificate chain and host name, with OpenSSL and the system roots just like
+// the native TLS backend does, and then the pins. A pin may match the key of any certificate
+// of the verified chain, so that pinning an intermediate or root survives a renewal of the
+// server certificate. A server whose chain matches no pin at all is reported as a mismatch
+// even if its chain does not verify either.
+pub struct PinnedVerifier {
+    root_certificate: Option<X509>,
+    pins: Vec<String>,
+    // Key of the last server that did not match, for the error of the failed request
+    mismatch: Mutex<Option<String>>,
+}
+
+impl PinnedVerifier {
+    pub fn new(root_certificate: Option<X509>, pins: Vec<String>) -> Self {
+        PinnedVerifier {
+            root_certificate,
+            pins,
+            mismatch: Mutex::new(None),
+        }
+    }
+
+    pub fn take_mismatch(&self) -> Option<String> {
+        self.mismatch.lock().unwrap().take()
+    }
+
+    fn matches(&self, chain_pins: &[String]) -> bool {
+        chain_pins.iter().any(|pin| self.pins.contains(pin))
+    }
+
+    fn verify_chain(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+    ) -> Result<Vec<String>> {
+        let mut param = X509VerifyParam::new()?;
+        match server_name {
+            ServerName::DnsName(name) => param.set_host(name.as_ref())?,
+            ServerName::IpAddress(ip) => param.set_ip(*ip)?,
+            _ => bail!("Unsupported server name"),
+        }
+
+        let mut store = X509StoreBuilder::new()?;
+        store.set_default_paths()?;
+        if let Some(ref root_certificate) = self.root_certificate {
+            store.add_cert(root_certificate.clone())?;
+        }
+        store.set_param(&param)?;
+        let store = store.build();
+
+        let certificate = X509::from_der(&end_entity.0)?;
+
+        let mut chain = Stack::new()?;
+        for intermediate in intermediates {
+            chain.push(X509::from_der(&intermediate.0)?)?;
+        }
+
+        let mut context = X509StoreContext::new()?;
+        let (verified, error, verified_chain) =
+            context.init(&store, &certificate, &chain, |context| {
+                let verified = context.verify_cert()?;
+                let verified_chain = context
+                    .chain()
+                    .map(|chain| chain.iter().map(X509Ref::to_owned).collect::<Vec<_>>())
+                    .unwrap_or_default();
+                Ok((verified, context.error(), verified_chain))
+            })?;
+
+        if !verified {
+            bail!("Server certificate verification failed: {}", error);
+        }
+
+        verified_chain
+            .iter()
+            .map(|certificate| certificate_pin(certificate))
+            .collect()
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verify_chain(end_entity, intermediates, server_name);
+
+        let matched = match verified {
+            Ok(ref chain_pins) => self.matches(chain_pins),
+            // Only a server presenting a pinned key learns why its chain was refused
+            Err(_) => {
+                let mut presented_pins = Vec::new();
+                for certificate in std::iter::once(end_entity).chain(intermediates) {
+                    presented_pins
+                        .push(spki_pin(&certificate.0).map_err(|err| general_error(&err))?);
+                }
+                self.matches(&presented_pins)
+            }
+        };
+
+        if !matched {
+            let pin = spki_pin(&end_entity.0).map_err(|err| general_error(&err))?;
+            *self.mismatch.lock().unwrap() = Some(pin);
+            return Err(rustls::Error::General("Public key pin mismatch".into()));
+        }
+
+        verified.map_err(|err| general_error(&err))?;
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn general_error(err: &anyhow::Error) -> rustls::Error {
+    rustls::Error::General(format!("{err:#}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+
+    #[test]
+    fn spki_pin_of_certificate() {
+        let certificate = X509::from_pem(test_utils::CERTIFICATE.as_bytes()).unwrap();
+        let pin = spki_pin(&certificate.to_der().unwrap()).unwrap();
+
+        assert_eq!(pin, test_utils::CERTIFICATE_PIN);
+        assert_eq!(
+            parse_pins(&json!([format!("sha256/{pin}")])).unwrap(),
+            vec![pin]
+        );
+    }
+
+    fn certificate(
+        common_name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+
+        match issuer {
+            Some((issuer, issuer_key)) => {
+                builder.set_issuer_name(issuer.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder.set_issuer_name(&name).unwrap();
+                builder.sign(key, MessageDigest::sha256()).unwrap();
+            }
+        }
+
+        builder.build()
+    }
+
+    fn verify(verifier: &PinnedVerifier, leaf: &X509) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            &Certificate(leaf.to_der().unwrap()),
+            &[],
+            &ServerName::try_from("localhost").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+    }
+
+    #[test]
+    fn pin_anywhere_in_the_chain() {
+        let ca_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let ca = certificate("Test CA", &ca_key, None);
+
+        let leaf_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let leaf = certificate("localhost", &leaf_key, Some((&ca, &ca_key)));
+
+        let ca_pin = certificate_pin(&ca).unwrap();
+        let leaf_pin = certificate_pin(&leaf).unwrap();
+
+        // A renewed server certificate still chains to the pinned CA key
+        let verifier = PinnedVerifier::new(Some(ca.clone()), vec![ca_pin]);
+        assert!(verify(&verifier, &leaf).is_ok());
+        assert_eq!(verifier.take_mismatch(), None);
+
+        let verifier = PinnedVerifier::new(Some(ca.clone()), vec![leaf_pin.clone()]);
+        assert!(verify(&verifier, &leaf).is_ok());
+
+        let verifier = PinnedVerifier::new(
+            Some(ca),
+            vec!["47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".into()],
+        );
+        assert!(verify(&verifier, &leaf).is_err());
+        assert_eq!(verifier.take_mismatch(), Some(leaf_pin));
+    }
+
+    #[test]
+    fn no_pins() {
+        assert!(get_api_pins(&ConfigMap::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a SHA-256 hash")]
+    fn reject_short_pin() {
+        parse_pins(&json!(["c2hvcnQ="])).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "`balenaApiPins` should be an array of strings")]
+    fn reject_pin_string() {
+        parse_pins(&json!("sha256/AAAA")).unwrap();
+    }
+}
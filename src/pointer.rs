@@ -0,0 +1,156 @@
+// Pointer module
+//
+// RFC 6901 JSON pointers into config.json, e.g. `/os/udevRules/56`, so that
+// migrations can change a single node of a nested object instead of the whole
+// top-level key. Keys that do not start with `/` keep naming top-level keys.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+// Reference tokens of a config.json key or pointer, the first one is always the top-level key
+pub fn parse_pointer(key: &str) -> Vec<String> {
+    match key.strip_prefix('/') {
+        Some(pointer) => pointer
+            .split('/')
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None => vec![key.to_string()],
+    }
+}
+
+// A whitelisted key or pointer covers everything below it as well
+pub fn is_whitelisted(whitelist: &[String], tokens: &[String]) -> bool {
+    whitelist
+        .iter()
+        .any(|entry| tokens.starts_with(&parse_pointer(entry)))
+}
+
+pub fn get_node<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |node, token| match node {
+        Value::Object(map) => map.get(token),
+        Value::Array(items) => token.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+// Missing objects along the path are created, `-` or the length of an array appends to it.
+// Appending is not idempotent, migrations skip an append of a value the array already holds.
+pub fn set_node(node: &mut Value, path: &[String], value: Value) -> Result<()> {
+    let Some((token, rest)) = path.split_first() else {
+        *node = value;
+        return Ok(());
+    };
+
+    if node.is_null() {
+        *node = Value::Object(Map::new());
+    }
+
+    match node {
+        Value::Object(map) => {
+            let child = map.entry(token.clone()).or_insert(Value::Null);
+            set_node(child, rest, value)
+        }
+        Value::Array(items) => {
+            let index = array_index(items, token)?;
+            if index == items.len() {
+                items.push(Value::Null);
+            }
+            set_node(&mut items[index], rest, value)
+        }
+        _ => bail!("Parent of `{}` is neither an object nor an array", token),
+    }
+}
+
+pub fn remove_node(node: &mut Value, path: &[String]) -> Option<Value> {
+    let (token, rest) = path.split_first()?;
+
+    match node {
+        Value::Object(map) if rest.is_empty() => map.remove(token),
+        Value::Object(map) => remove_node(map.get_mut(token)?, rest),
+        Value::Array(items) => {
+            let index = token.parse::<usize>().ok().filter(|i| *i < items.len())?;
+            if rest.is_empty() {
+                Some(items.remove(index))
+            } else {
+                remove_node(&mut items[index], rest)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn array_index(items: &[Value], token: &str) -> Result<usize> {
+    if token == "-" {
+        return Ok(items.len());
+    }
+
+    token
+        .parse::<usize>()
+        .ok()
+        .filter(|index| *index <= items.len())
+        .with_context(|| format!("`{token}` is not an index of an array of {}", items.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(key: &str) -> Vec<String> {
+        parse_pointer(key)
+    }
+
+    #[test]
+    fn parse_pointers_and_keys() {
+        assert_eq!(tokens("logsEndpoint"), vec!["logsEndpoint"]);
+        assert_eq!(tokens("/os/udevRules/56"), vec!["os", "udevRules", "56"]);
+        assert_eq!(tokens("/a~1b/c~0d"), vec!["a/b", "c~d"]);
+    }
+
+    #[test]
+    fn whitelist_covers_children() {
+        let whitelist = vec!["logsEndpoint".to_string(), "/os/udevRules".to_string()];
+
+        assert!(is_whitelisted(&whitelist, &tokens("/logsEndpoint")));
+        assert!(is_whitelisted(&whitelist, &tokens("/os/udevRules/56")));
+        assert!(!is_whitelisted(&whitelist, &tokens("/os/sshKeys")));
+        assert!(!is_whitelisted(&whitelist, &tokens("os")));
+    }
+
+    #[test]
+    fn set_nested_nodes() {
+        let mut value = json!({"udevRules": {"10": "old"}, "sshKeys": ["a"]});
+
+        set_node(&mut value, &tokens("/udevRules/56"), json!("new")).unwrap();
+        set_node(&mut value, &tokens("/sshKeys/-"), json!("b")).unwrap();
+        set_node(&mut value, &tokens("/network/wifi/ssid"), json!("home")).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "udevRules": {"10": "old", "56": "new"},
+                "sshKeys": ["a", "b"],
+                "network": {"wifi": {"ssid": "home"}}
+            })
+        );
+        assert_eq!(get_node(&value, &tokens("/sshKeys/1")), Some(&json!("b")));
+    }
+
+    #[test]
+    #[should_panic(expected = "`5` is not an index of an array of 1")]
+    fn reject_index_past_end() {
+        let mut value = json!({"sshKeys": ["a"]});
+        set_node(&mut value, &tokens("/sshKeys/5"), json!("b")).unwrap();
+    }
+
+    #[test]
+    fn remove_nested_node() {
+        let mut value = json!({"udevRules": {"10": "old", "56": "new"}});
+
+        assert_eq!(
+            remove_node(&mut value, &tokens("/udevRules/56")),
+            Some(json!("new"))
+        );
+        assert_eq!(remove_node(&mut value, &tokens("/udevRules/56")), None);
+        assert_eq!(value, json!({"udevRules": {"10": "old"}}));
+    }
+}
@@ -0,0 +1,187 @@
+// Proxy module
+//
+// Routes configuration fetches through the proxy the device is set up with.
+// balenaOS keeps its system proxy as a redsocks configuration in
+// `system-proxy/redsocks.conf` on the boot partition, with hosts that bypass
+// it listed one per line in `system-proxy/no_proxy`. An explicit proxy URL
+// takes precedence over both.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::fs::read_file;
+
+const SYSTEM_PROXY_DIR: &str = "system-proxy";
+const REDSOCKS_CONF: &str = "redsocks.conf";
+const NO_PROXY: &str = "no_proxy";
+
+pub fn get_proxy(explicit: Option<&str>, boot_dir: &Path) -> Result<Option<reqwest::Proxy>> {
+    if let Some(url) = explicit {
+        let proxy = reqwest::Proxy::all(url).context(format!("Invalid proxy URL `{url}`"))?;
+        return Ok(Some(proxy));
+    }
+
+    let dir = boot_dir.join(SYSTEM_PROXY_DIR);
+
+    let redsocks_path = dir.join(REDSOCKS_CONF);
+
+    if !redsocks_path.exists() {
+        return Ok(None);
+    }
+
+    let settings = parse_redsocks(&read_file(&redsocks_path)?)
+        .context(format!("Parsing {redsocks_path:?} failed"))?;
+
+    let mut proxy = reqwest::Proxy::all(&settings.url)?;
+
+    if let Some((ref login, ref password)) = settings.credentials {
+        proxy = proxy.basic_auth(login, password);
+    }
+
+    let no_proxy_path = dir.join(NO_PROXY);
+    if no_proxy_path.exists() {
+        let no_proxy = parse_no_proxy(&read_file(&no_proxy_path)?);
+        proxy = proxy.no_proxy(reqwest::NoProxy::from_string(&no_proxy));
+    }
+
+    debug!("Using system proxy {}", settings.url);
+
+    Ok(Some(proxy))
+}
+
+#[derive(Debug, PartialEq)]
+struct ProxySettings {
+    url: String,
+    credentials: Option<(String, String)>,
+}
+
+// Only the `redsocks` section matters, e.g.
+//
+//     redsocks {
+//         type = socks5;
+//         ip = 10.0.0.1;
+//         port = 1080;
+//         login = "user";
+//         password = "secret";
+//     }
+fn parse_redsocks(contents: &str) -> Result<ProxySettings> {
+    let mut fields = HashMap::new();
+    let mut in_redsocks = false;
+
+    for line in contents.lines() {
+        let line = line.split("//").next().unwrap_or("").trim();
+
+        if !in_redsocks {
+            in_redsocks = line.starts_with("redsocks") && line.ends_with('{');
+            continue;
+        }
+
+        if line.starts_with('}') {
+            break;
+        }
+
+        for statement in line.split(';') {
+            if let Some((key, value)) = statement.split_once('=') {
+                let value = value.trim().trim_matches('"');
+                fields.insert(key.trim().to_string(), value.to_string());
+            }
+        }
+    }
+
+    let field = |key: &str| {
+        fields
+            .get(key)
+            .cloned()
+            .with_context(|| format!("`redsocks` section has no `{key}`"))
+    };
+
+    let scheme = match field("type")?.as_str() {
+        "socks5" => "socks5",
+        "http-connect" | "http-relay" => "http",
+        other => bail!("Unsupported proxy type `{}`", other),
+    };
+
+    let url = format!("{}://{}:{}", scheme, field("ip")?, field("port")?);
+
+    let credentials = match (fields.get("login"), fields.get("password")) {
+        (Some(login), Some(password)) => Some((login.clone(), password.clone())),
+        (Some(login), None) => Some((login.clone(), String::new())),
+        _ => None,
+    };
+
+    Ok(ProxySettings { url, credentials })
+}
+
+fn parse_no_proxy(contents: &str) -> String {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOCKS5_CONF: &str = r#"
+        base {
+            log_debug = off;
+            log_info = on;
+            log = stderr;
+            daemon = off;
+            redirector = iptables;
+        }
+
+        redsocks {
+            type = socks5;
+            ip = 10.0.0.1;
+            port = 1080;
+            login = "user";
+            password = "secret"; // rotated yearly
+            local_ip = 127.0.0.1;
+            local_port = 12345;
+        }
+    "#;
+
+    #[test]
+    fn parse_socks5_redsocks_conf() {
+        assert_eq!(
+            parse_redsocks(SOCKS5_CONF).unwrap(),
+            ProxySettings {
+                url: "socks5://10.0.0.1:1080".into(),
+                credentials: Some(("user".into(), "secret".into())),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_http_connect_redsocks_conf() {
+        let conf = "redsocks {\n type = http-connect; ip = proxy.local; port = 3128;\n}\n";
+
+        assert_eq!(
+            parse_redsocks(conf).unwrap(),
+            ProxySettings {
+                url: "http://proxy.local:3128".into(),
+                credentials: None,
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported proxy type `socks4`")]
+    fn reject_socks4() {
+        parse_redsocks("redsocks {\n type = socks4; ip = 10.0.0.1; port = 1080;\n}\n").unwrap();
+    }
+
+    #[test]
+    fn parse_no_proxy_list() {
+        assert_eq!(
+            parse_no_proxy("# local\n192.168.0.0/16\n\n  registry.local \n"),
+            "192.168.0.0/16,registry.local"
+        );
+    }
+}
@@ -19,6 +19,20 @@ pub fn fill_random(dest: &mut [u8]) {
     }
 }
 
+// Uniformly distributed between zero and `max`, in millisecond steps
+pub fn random_duration(max: Duration) -> Duration {
+    let max_millis = max.as_millis() as u64;
+
+    if max_millis == 0 {
+        return Duration::ZERO;
+    }
+
+    let mut buf = [0; 8];
+    fill_random(&mut buf);
+
+    Duration::from_millis(u64::from_ne_bytes(buf) % (max_millis + 1))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -30,4 +44,13 @@ mod tests {
         fill_random(&mut random);
         assert_ne!(zeros, random);
     }
+
+    #[test]
+    fn random_duration_within_bounds() {
+        assert_eq!(random_duration(Duration::ZERO), Duration::ZERO);
+
+        for _ in 0..100 {
+            assert!(random_duration(Duration::from_secs(2)) <= Duration::from_secs(2));
+        }
+    }
 }
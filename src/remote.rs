@@ -1,31 +1,105 @@
 use std::collections::HashMap;
-use std::thread;
-use std::time::Duration;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::blocking::Response;
+use reqwest::header::{
+    CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+};
+use reqwest::StatusCode;
+
+use openssl::x509::X509;
+
+use crate::ack::AppliedState;
+use crate::config_json::{get_api_key, get_api_key_for_endpoint, read_config_json};
+use crate::exit::Failure;
+use crate::fs;
+use crate::identity::ClientIdentity;
+use crate::pin::{PinnedVerifier, API_PINS_KEY};
+use crate::retry::RetryPolicy;
+use crate::signal;
+use crate::signature::{verify_signature, SigningKey};
 
-use anyhow::{anyhow, Context, Result};
+pub type OverridesMap = HashMap<String, serde_json::Value>;
 
-use crate::args::get_config_json_path;
-use crate::config_json::{get_api_key, read_config_json};
+// Longest part of an error response body that ends up in the error message
+const BODY_EXCERPT_LENGTH: usize = 200;
 
-pub type OverridesMap = HashMap<String, serde_json::Value>;
+// Detached signature of the response body
+pub const SIGNATURE_HEADER: &str = "X-Config-Signature";
+
+// Configuration of a directory source, its detached signature is kept next to it with an
+// additional `.sig` extension
+const LOCAL_CONFIGURATION: &str = "os-config-api.json";
+const SIGNATURE_EXTENSION: &str = ".sig";
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct RemoteConfiguration {
-    pub services: HashMap<String, HashMap<String, String>>,
+    pub services: HashMap<String, HashMap<String, FileContents>>,
     pub config: ConfigMigrationInstructions,
 }
 
+// Contents of a configuration file, plain text or e.g. `{ "encoding": "base64", "content": ... }`
+// for certificates, keytabs and other binary files
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum FileContents {
+    Text(String),
+    Encoded { encoding: Encoding, content: String },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Base64,
+}
+
+impl FileContents {
+    // Text stays text, so that existing configurations and generations keep looking the same
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => FileContents::Text(text),
+            Err(err) => FileContents::Encoded {
+                encoding: Encoding::Base64,
+                content: STANDARD.encode(err.into_bytes()),
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            FileContents::Text(text) => Ok(text.as_bytes().to_vec()),
+            FileContents::Encoded {
+                encoding: Encoding::Base64,
+                content,
+            } => Ok(STANDARD
+                .decode(content)
+                .context("Base64 content decoding failed")?),
+        }
+    }
+}
+
+impl From<&str> for FileContents {
+    fn from(text: &str) -> Self {
+        FileContents::Text(text.into())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct ConfigMigrationInstructions {
     pub overrides: OverridesMap,
+    // Keys to remove from config.json, e.g. stale endpoints or deprecated flags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deletes: Vec<String>,
 }
 
 impl RemoteConfiguration {
-    pub fn get_config_contents<'a>(
-        &'a self,
-        service_id: &str,
-        config_name: &str,
-    ) -> Result<&'a str> {
+    pub fn get_config_contents(&self, service_id: &str, config_name: &str) -> Result<Vec<u8>> {
         let contents_map = self
             .services
             .get(service_id)
@@ -39,7 +113,220 @@ impl RemoteConfiguration {
             )
         })?;
 
-        Ok(contents as &str)
+        contents.to_bytes().context(format!(
+            "Decoding service `{service_id}` config `{config_name}` failed"
+        ))
+    }
+}
+
+// Cache validators of the last applied configuration response, sent along with the next
+// request so that an unchanged configuration is not downloaded again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Validators {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+// An API serving the configuration route, `apiEndpoint` first and then its mirrors
+pub struct Endpoint {
+    pub api_endpoint: String,
+    pub root_certificate: Option<X509>,
+    // SPKI pins the server key has to match, none disables pinning
+    pub pins: Vec<String>,
+}
+
+// How the remote configuration is fetched and what it is checked against
+pub struct FetchOptions {
+    // Client certificate for APIs that require mutual TLS
+    pub identity: Option<ClientIdentity>,
+    pub proxy: Option<reqwest::Proxy>,
+    pub retry_policy: RetryPolicy,
+    pub validators: Option<Validators>,
+    // Payloads not signed with this key are rejected
+    pub signing_key: Option<SigningKey>,
+}
+
+// A configuration as received, kept so that its signature can be verified again when it is
+// reapplied from the cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload {
+    pub body: String,
+    pub signature: Option<String>,
+}
+
+pub enum Fetched {
+    Modified(Box<RemoteConfiguration>, Payload, Option<Validators>),
+    NotModified,
+}
+
+// An endpoint ready to be asked for the configuration
+struct Source<'a> {
+    endpoint: &'a Endpoint,
+    url: String,
+    api_key: String,
+    client: Client,
+}
+
+// A client of an endpoint, along with the verifier of its pins if it has any
+struct Client {
+    http: reqwest::blocking::Client,
+    verifier: Option<Arc<PinnedVerifier>>,
+}
+
+impl Client {
+    // A handshake that failed on the pins fails the request with the server key it offered
+    fn send(&self, request: reqwest::blocking::RequestBuilder) -> Result<Response> {
+        request.send().map_err(|err| {
+            match self
+                .verifier
+                .as_ref()
+                .and_then(|verifier| verifier.take_mismatch())
+            {
+                Some(pin) => FetchError::PinMismatch { pin }.into(),
+                None => err.into(),
+            }
+        })
+    }
+}
+
+enum Body {
+    Modified {
+        body: String,
+        validators: Option<Validators>,
+        signature: Option<String>,
+    },
+    NotModified,
+}
+
+// Responses of the configuration endpoint that are not a configuration
+#[derive(Debug, PartialEq)]
+pub enum FetchError {
+    PinMismatch {
+        pin: String,
+    },
+    Unauthorized {
+        body: String,
+    },
+    NotFound {
+        body: String,
+    },
+    RateLimited {
+        retry_after: Option<Duration>,
+        body: String,
+    },
+    Server {
+        status: StatusCode,
+        body: String,
+    },
+    Client {
+        status: StatusCode,
+        body: String,
+    },
+}
+
+impl FetchError {
+    fn from_response(
+        status: StatusCode,
+        retry_after: Option<&str>,
+        body: &str,
+    ) -> Option<FetchError> {
+        let body = body_excerpt(body);
+
+        match status {
+            _ if status.is_success() => None,
+            StatusCode::UNAUTHORIZED => Some(FetchError::Unauthorized { body }),
+            StatusCode::NOT_FOUND => Some(FetchError::NotFound { body }),
+            StatusCode::TOO_MANY_REQUESTS => Some(FetchError::RateLimited {
+                // Only the delay-seconds form of `Retry-After` is honoured
+                retry_after: retry_after
+                    .and_then(|value| value.trim().parse().ok())
+                    .map(Duration::from_secs),
+                body,
+            }),
+            _ if status.is_server_error() => Some(FetchError::Server { status, body }),
+            _ => Some(FetchError::Client { status, body }),
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    // Whether asking again later may succeed
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            FetchError::RateLimited { .. } | FetchError::Server { .. }
+        )
+    }
+}
+
+// Whether the API could not be reached or kept failing transiently, as opposed to refusing
+// the device or sending something that must not be applied
+pub fn is_unreachable(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(fetch_err) = cause.downcast_ref::<FetchError>() {
+            fetch_err.is_transient()
+        } else if let Some(reqwest_err) = cause.downcast_ref::<reqwest::Error>() {
+            !reqwest_err.is_builder() && !reqwest_err.is_decode()
+        } else {
+            false
+        }
+    })
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::PinMismatch { pin } => write!(
+                f,
+                "Public key pin mismatch, server key sha256/{pin} is not listed in `{API_PINS_KEY}`"
+            ),
+            FetchError::Unauthorized { body } => {
+                write!(f, "Unauthorized, check deviceApiKey: {body}")
+            }
+            FetchError::NotFound { body } => {
+                write!(f, "Configuration route not found on the API: {body}")
+            }
+            FetchError::RateLimited {
+                retry_after: Some(retry_after),
+                body,
+            } => write!(
+                f,
+                "Rate limited, retry after {}s: {body}",
+                retry_after.as_secs()
+            ),
+            FetchError::RateLimited {
+                retry_after: None,
+                body,
+            } => write!(f, "Rate limited: {body}"),
+            FetchError::Server { status, body } => write!(f, "Server error {status}: {body}"),
+            FetchError::Client { status, body } => {
+                write!(f, "Request failed with {status}: {body}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+fn body_excerpt(body: &str) -> String {
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if body.chars().count() > BODY_EXCERPT_LENGTH {
+        format!(
+            "{}...",
+            body.chars().take(BODY_EXCERPT_LENGTH).collect::<String>()
+        )
+    } else {
+        body
     }
 }
 
@@ -48,109 +335,402 @@ pub fn config_url(api_endpoint: &str, config_route: &str) -> String {
 }
 
 pub fn fetch_configuration(
-    config_url: &str,
-    root_certificate: Option<reqwest::Certificate>,
-    retry: bool,
-) -> Result<RemoteConfiguration> {
-    fetch_configuration_impl(config_url, root_certificate, retry)
-        .context("Fetching configuration failed")
+    endpoints: &[Endpoint],
+    config_route: &str,
+    config_json_path: &Path,
+    options: FetchOptions,
+) -> Result<Fetched> {
+    fetch_configuration_impl(endpoints, config_route, config_json_path, options)
+        .context(Failure::Fetch("Fetching configuration failed".into()))
 }
 
 fn fetch_configuration_impl(
-    config_url: &str,
-    root_certificate: Option<reqwest::Certificate>,
-    retry: bool,
-) -> Result<RemoteConfiguration> {
-    let config_json = read_config_json(&get_config_json_path())?;
+    endpoints: &[Endpoint],
+    config_route: &str,
+    config_json_path: &Path,
+    options: FetchOptions,
+) -> Result<Fetched> {
+    let config_json = read_config_json(config_json_path)?;
     let api_key = get_api_key(&config_json)?.unwrap_or("".to_string());
 
     if !api_key.is_empty() {
         debug!("using auth token {:.7}...", api_key);
     }
 
-    let client = build_reqwest_client(root_certificate)?;
+    let mut sources = Vec::new();
+
+    for (index, endpoint) in endpoints.iter().enumerate() {
+        // The key of `apiEndpoint` is never handed to a mirror, which may be run by a third party
+        let api_key = if index == 0 {
+            api_key.clone()
+        } else if let Some(api_key) =
+            get_api_key_for_endpoint(&config_json, &endpoint.api_endpoint)?
+        {
+            api_key
+        } else {
+            warn!(
+                "Skipping mirror {} without a key of its own in `deviceApiKeys`",
+                endpoint.api_endpoint
+            );
+            continue;
+        };
+
+        sources.push(Source {
+            endpoint,
+            url: config_url(&endpoint.api_endpoint, config_route),
+            api_key,
+            client: build_client(endpoint, &options)?,
+        });
+    }
+
+    let Some(primary) = sources.first() else {
+        bail!("No API endpoint to fetch the configuration from");
+    };
+
+    info!("Fetching service configuration from {}...", primary.url);
+
+    let (index, body) = retry_request_config(&sources, &options)?;
+
+    match body {
+        Body::Modified {
+            body,
+            validators,
+            signature,
+        } => {
+            if index == 0 {
+                info!("Service configuration retrieved");
+            } else {
+                info!(
+                    "Service configuration retrieved from mirror {}",
+                    sources[index].endpoint.api_endpoint
+                );
+            }
+
+            let payload = Payload { body, signature };
+            let remote_config = parse_payload(&payload, options.signing_key.as_ref())?;
+
+            Ok(Fetched::Modified(
+                Box::new(remote_config),
+                payload,
+                validators,
+            ))
+        }
+        Body::NotModified => {
+            info!("Service configuration not modified");
+
+            Ok(Fetched::NotModified)
+        }
+    }
+}
+
+// Reported once to the primary endpoint only, the next apply reports again anyway
+pub fn send_state(
+    endpoint: &Endpoint,
+    state_route: &str,
+    config_json_path: &Path,
+    options: &FetchOptions,
+    state: &AppliedState,
+) -> Result<()> {
+    let config_json = read_config_json(config_json_path)?;
+    let api_key = get_api_key(&config_json)?.unwrap_or("".to_string());
+
+    let url = config_url(&endpoint.api_endpoint, state_route);
+
+    info!("Reporting configuration state to {}...", url);
+
+    let client = build_client(endpoint, options)?;
 
-    let request_fn = if retry {
-        retry_request_config
+    let request = client
+        .http
+        .post(&url)
+        .bearer_auth(&api_key)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(state)?);
+
+    let response = client.send(request)?;
+
+    let status = response.status();
+    let body = response.text()?;
+
+    if let Some(err) = FetchError::from_response(status, None, &body) {
+        return Err(err.into());
+    }
+
+    info!("Configuration state reported");
+
+    Ok(())
+}
+
+// Reads the configuration from a file, or from a directory such as a bundle dropped on the
+// boot partition, for devices that never reach the API
+pub fn read_local_configuration(
+    source: &Path,
+    signing_key: Option<&SigningKey>,
+) -> Result<(RemoteConfiguration, Payload)> {
+    read_local_configuration_impl(source, signing_key).context(Failure::Fetch(format!(
+        "Reading configuration from {source:?} failed"
+    )))
+}
+
+fn read_local_configuration_impl(
+    source: &Path,
+    signing_key: Option<&SigningKey>,
+) -> Result<(RemoteConfiguration, Payload)> {
+    let path = if source.is_dir() {
+        source.join(LOCAL_CONFIGURATION)
     } else {
-        request_config
+        source.to_path_buf()
     };
 
-    info!("Fetching service configuration from {}...", config_url);
+    info!("Reading service configuration from {}...", path.display());
+
+    let body = fs::read_file(&path)?;
+
+    let mut signature_path = path.into_os_string();
+    signature_path.push(SIGNATURE_EXTENSION);
+    let signature_path = PathBuf::from(signature_path);
 
-    let json_data = request_fn(config_url, &api_key, &client)?.text()?;
+    let signature = if signature_path.exists() {
+        Some(fs::read_file(&signature_path)?)
+    } else {
+        None
+    };
 
     info!("Service configuration retrieved");
 
-    Ok(serde_json::from_str(&json_data)?)
-}
+    let payload = Payload { body, signature };
+    let remote_config = parse_payload(&payload, signing_key)?;
 
-fn request_config(
-    url: &str,
-    token: &str,
-    client: &reqwest::blocking::Client,
-) -> Result<reqwest::blocking::Response> {
-    Ok(client.get(url).bearer_auth(token).send()?)
+    Ok((remote_config, payload))
 }
 
-fn retry_request_config(
-    url: &str,
-    token: &str,
-    client: &reqwest::blocking::Client,
-) -> Result<reqwest::blocking::Response> {
-    let mut sleeped = 0;
+// Nothing of an unverified payload is parsed, let alone applied
+pub fn parse_payload(
+    payload: &Payload,
+    signing_key: Option<&SigningKey>,
+) -> Result<RemoteConfiguration> {
+    if let Some(signing_key) = signing_key {
+        let Some(ref signature) = payload.signature else {
+            bail!("Service configuration is not signed");
+        };
 
-    let mut last_err = String::new();
+        verify_signature(signing_key, payload.body.as_bytes(), signature)
+            .context("Verifying service configuration signature failed")?;
 
-    loop {
-        match client.get(url).bearer_auth(token).send() {
-            Ok(response) => {
-                return Ok(response);
+        info!("Service configuration signature verified");
+    }
+
+    Ok(serde_json::from_str(&payload.body)?)
+}
+
+// Asks every source in order. The error returned is the one that decides about retrying,
+// so that a mirror which may recover keeps the retries going.
+fn request_any(sources: &[Source], options: &FetchOptions) -> Result<(usize, Body)> {
+    let mut errors = Vec::new();
+
+    for (index, source) in sources.iter().enumerate() {
+        match request_config(source, options) {
+            Ok(body) => return Ok((index, body)),
+            // An intercepted endpoint is not worked around by asking the next one
+            Err(err) if matches!(err.downcast_ref(), Some(FetchError::PinMismatch { .. })) => {
+                return Err(err);
             }
             Err(err) => {
-                // Print the same error only once.
-                let curr_err = format!("{err}");
-                if last_err != curr_err {
-                    info!("{}", curr_err);
-                    last_err = curr_err;
+                if sources.len() > 1 {
+                    debug!("Fetching from {} failed: {:#}", source.url, err);
                 }
+                errors.push(err);
             }
         }
+    }
 
-        let sleep = if sleeped < 10 {
-            1
-        } else if sleeped < 30 {
-            2
-        } else if sleeped < 60 {
-            5
-        } else if sleeped < 300 {
-            10
-        } else {
-            30
+    let decisive = errors
+        .iter()
+        .position(|err| {
+            err.downcast_ref::<FetchError>()
+                .map_or(true, FetchError::is_transient)
+        })
+        .unwrap_or(0);
+
+    Err(errors.swap_remove(decisive))
+}
+
+fn request_config(source: &Source, options: &FetchOptions) -> Result<Body> {
+    let url = &source.url;
+
+    // Validators are only good for the URL that issued them
+    let validators = options
+        .validators
+        .as_ref()
+        .filter(|validators| validators.url == *url);
+
+    let mut request = source.client.http.get(url).bearer_auth(&source.api_key);
+
+    if let Some(validators) = validators {
+        if let Some(ref etag) = validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(ref last_modified) = validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = source.client.send(request)?;
+
+    let status = response.status();
+
+    if status == StatusCode::NOT_MODIFIED && validators.is_some() {
+        return Ok(Body::NotModified);
+    }
+
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    let etag = header(ETAG.as_str());
+    let last_modified = header(LAST_MODIFIED.as_str());
+    let signature = header(SIGNATURE_HEADER);
+
+    let retry_after = header(RETRY_AFTER.as_str());
+
+    let body = response.text()?;
+
+    if let Some(err) = FetchError::from_response(status, retry_after.as_deref(), &body) {
+        return Err(err.into());
+    }
+
+    let validators = if etag.is_some() || last_modified.is_some() {
+        Some(Validators {
+            url: url.clone(),
+            etag,
+            last_modified,
+        })
+    } else {
+        None
+    };
+
+    Ok(Body::Modified {
+        body,
+        validators,
+        signature,
+    })
+}
+
+fn retry_request_config(sources: &[Source], options: &FetchOptions) -> Result<(usize, Body)> {
+    let retry_policy = &options.retry_policy;
+
+    let start = Instant::now();
+
+    let mut attempts = 0;
+
+    let mut last_err = String::new();
+
+    loop {
+        attempts += 1;
+
+        let err = match request_any(sources, options) {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+
+        let retry_after = match err.downcast_ref::<FetchError>() {
+            Some(fetch_err) if !fetch_err.is_transient() => return Err(err),
+            Some(fetch_err) => fetch_err.retry_after(),
+            None => None,
         };
 
-        thread::sleep(Duration::from_secs(sleep));
+        let delay = retry_policy
+            .backoff(attempts)
+            .max(retry_after.unwrap_or_default());
 
-        sleeped += sleep;
+        if retry_policy.gives_up(attempts, start.elapsed() + delay) {
+            if attempts == 1 {
+                return Err(err);
+            }
+
+            return Err(err.context(format!(
+                "Gave up after {} attempts / {} seconds",
+                attempts,
+                start.elapsed().as_secs()
+            )));
+        }
 
-        if sleeped % 10 == 0 {
-            info!("Awaiting service configuration...")
+        // Print the same error only once.
+        let curr_err = format!("{err}");
+        if last_err != curr_err {
+            info!("{}", curr_err);
+            last_err = curr_err;
+        }
+
+        // Not a transient error, so that an update does not fall back to the cache either
+        if !signal::sleep_unless_terminated(delay) {
+            return Err(anyhow!(Failure::Fetch(format!(
+                "Terminating, stopped retrying: {last_err}"
+            ))));
         }
     }
 }
 
-fn build_reqwest_client(
-    root_certificate: Option<reqwest::Certificate>,
-) -> Result<reqwest::blocking::Client> {
-    let client = if let Some(root_certificate) = root_certificate {
-        reqwest::blocking::Client::builder()
-            .add_root_certificate(root_certificate)
-            .build()?
+// The native TLS backend has no hook into certificate verification, so pinned endpoints
+// are handshaked with rustls and a verifier that checks the pins
+fn build_client(endpoint: &Endpoint, options: &FetchOptions) -> Result<Client> {
+    let mut builder = reqwest::blocking::Client::builder()
+        .connect_timeout(options.retry_policy.connect_timeout)
+        .timeout(options.retry_policy.read_timeout);
+
+    let mut verifier = None;
+
+    if endpoint.pins.is_empty() {
+        if let Some(ref root_certificate) = endpoint.root_certificate {
+            builder = builder
+                .add_root_certificate(reqwest::Certificate::from_der(&root_certificate.to_der()?)?);
+        }
+
+        if let Some(ref identity) = options.identity {
+            builder = builder.identity(identity.to_reqwest()?);
+        }
     } else {
-        reqwest::blocking::Client::new()
-    };
+        // Pins are checked in the TLS handshake, a plain HTTP endpoint would get the key unchecked
+        if !endpoint.api_endpoint.starts_with("https://") {
+            bail!(
+                "`balenaApiPins` require an https endpoint, {} is not one",
+                endpoint.api_endpoint
+            );
+        }
+
+        let pinned_verifier = Arc::new(PinnedVerifier::new(
+            endpoint.root_certificate.clone(),
+            endpoint.pins.clone(),
+        ));
 
-    Ok(client)
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(pinned_verifier.clone());
+
+        let config = match options.identity {
+            Some(ref identity) => {
+                let (chain, key) = identity.to_rustls()?;
+                config.with_client_auth_cert(chain, key)?
+            }
+            None => config.with_no_client_auth(),
+        };
+
+        builder = builder.use_preconfigured_tls(config).https_only(true);
+        verifier = Some(pinned_verifier);
+    }
+
+    if let Some(ref proxy) = options.proxy {
+        builder = builder.proxy(proxy.clone());
+    }
+
+    Ok(Client {
+        http: builder.build()?,
+        verifier,
+    })
 }
 
 #[cfg(test)]
@@ -158,6 +738,9 @@ mod tests {
 
     use super::*;
 
+    use crate::args::OsConfigSubcommand;
+    use crate::signature::parse_signing_key;
+
     const JSON_DATA: &str = r#"{
         "services": {
             "openvpn": {
@@ -197,9 +780,147 @@ mod tests {
                 overrides: hashmap! {
                     "logsEndpoint".into() => "https://logs.balenadev.io".into()
                 },
+                deletes: vec![],
             },
         };
 
         assert_eq!(parsed, expected);
     }
+
+    #[test]
+    fn parse_encoded_file_contents() {
+        let parsed: FileContents =
+            serde_json::from_str(r#"{ "encoding": "base64", "content": "AP8=" }"#).unwrap();
+
+        assert_eq!(parsed.to_bytes().unwrap(), vec![0x00, 0xff]);
+        assert_eq!(FileContents::from_bytes(vec![0x00, 0xff]), parsed);
+        assert_eq!(
+            FileContents::from_bytes(b"text".to_vec()),
+            FileContents::from("text")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "data did not match any variant of untagged enum FileContents")]
+    fn reject_unknown_encoding() {
+        serde_json::from_str::<FileContents>(r#"{ "encoding": "hex", "content": "00ff" }"#)
+            .unwrap();
+    }
+
+    #[test]
+    fn classify_responses() {
+        assert_eq!(FetchError::from_response(StatusCode::OK, None, "{}"), None);
+
+        assert_eq!(
+            FetchError::from_response(StatusCode::UNAUTHORIZED, None, "Unauthorized"),
+            Some(FetchError::Unauthorized {
+                body: "Unauthorized".into()
+            })
+        );
+
+        let rate_limited =
+            FetchError::from_response(StatusCode::TOO_MANY_REQUESTS, Some("120"), "Slow down")
+                .unwrap();
+        assert!(rate_limited.is_transient());
+        assert_eq!(
+            rate_limited.to_string(),
+            "Rate limited, retry after 120s: Slow down"
+        );
+
+        let server_error =
+            FetchError::from_response(StatusCode::SERVICE_UNAVAILABLE, None, "<html>\n<p>Down</p>")
+                .unwrap();
+        assert!(server_error.is_transient());
+        assert_eq!(
+            server_error.to_string(),
+            "Server error 503 Service Unavailable: <html> <p>Down</p>"
+        );
+
+        let not_found = FetchError::from_response(StatusCode::NOT_FOUND, None, "").unwrap();
+        assert!(!not_found.is_transient());
+    }
+
+    #[test]
+    fn unreachable_only_on_transient_errors() {
+        let server_error: anyhow::Error = FetchError::Server {
+            status: StatusCode::BAD_GATEWAY,
+            body: "".into(),
+        }
+        .into();
+        assert!(is_unreachable(
+            &server_error.context("Gave up after 3 attempts / 10 seconds")
+        ));
+
+        let unauthorized: anyhow::Error = FetchError::Unauthorized { body: "".into() }.into();
+        assert!(!is_unreachable(&unauthorized));
+
+        assert!(!is_unreachable(
+            &anyhow!("Signature does not match")
+                .context("Verifying service configuration signature failed")
+        ));
+    }
+
+    #[test]
+    fn truncate_body_excerpt() {
+        let body = "a".repeat(BODY_EXCERPT_LENGTH + 10);
+        assert_eq!(
+            body_excerpt(&body),
+            format!("{}...", "a".repeat(BODY_EXCERPT_LENGTH))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "`balenaApiPins` require an https endpoint")]
+    fn reject_pins_without_tls() {
+        let endpoint = Endpoint {
+            api_endpoint: "http://api.balena-cloud.com".into(),
+            root_certificate: None,
+            pins: vec!["47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".into()],
+        };
+
+        let options = FetchOptions {
+            identity: None,
+            proxy: None,
+            retry_policy: RetryPolicy::for_subcommand(&OsConfigSubcommand::Update),
+            validators: None,
+            signing_key: None,
+        };
+
+        build_client(&endpoint, &options)
+            .map(|_| ())
+            .map_err(|err| anyhow!("{:#}", err))
+            .unwrap();
+    }
+
+    #[test]
+    fn read_signed_local_configuration() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let signing_key = parse_signing_key(test_utils::signing_key().as_bytes()).unwrap();
+
+        let path = tmp_dir.path().join(LOCAL_CONFIGURATION);
+        ::std::fs::write(&path, JSON_DATA).unwrap();
+        ::std::fs::write(
+            tmp_dir.path().join("os-config-api.json.sig"),
+            test_utils::sign_config(JSON_DATA),
+        )
+        .unwrap();
+
+        let (parsed, payload) =
+            read_local_configuration(tmp_dir.path(), Some(&signing_key)).unwrap();
+        assert_eq!(parsed, serde_json::from_str(JSON_DATA).unwrap());
+        assert_eq!(payload.body, JSON_DATA);
+    }
+
+    #[test]
+    #[should_panic(expected = "Service configuration is not signed")]
+    fn reject_unsigned_local_configuration() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let signing_key = parse_signing_key(test_utils::signing_key().as_bytes()).unwrap();
+
+        let path = test_utils::create_tmp_file(&tmp_dir, "config.json", JSON_DATA, None);
+
+        read_local_configuration(Path::new(&path), Some(&signing_key))
+            .map_err(|err| anyhow!("{:#}", err))
+            .unwrap();
+    }
 }
@@ -0,0 +1,58 @@
+// Report module
+//
+// Collects the outcome of a subcommand so it can be emitted as a single
+// machine-readable document with `--output json`.
+
+use crate::config_json::GenerateApiKeyResult;
+use crate::exit::failure_class;
+use crate::status::DeviceStatus;
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub command: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generate_api_key: Option<GenerateApiKeyResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<DeviceStatus>,
+    pub unconfigured: bool,
+    pub dry_run: bool,
+    pub cached: bool,
+    pub service_config_changes: bool,
+    pub written_files: Vec<String>,
+    pub deleted_files: Vec<String>,
+    pub migrated_keys: Vec<String>,
+    pub restarted_units: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub message: String,
+    pub class: &'static str,
+}
+
+impl Report {
+    pub fn new(command: &'static str) -> Self {
+        Report {
+            command,
+            ..Default::default()
+        }
+    }
+
+    // Document emitted in place of the subcommand report when it fails
+    pub fn failed(command: &'static str, err: &anyhow::Error) -> Self {
+        Report {
+            error: Some(ErrorReport {
+                message: format!("{err:#}"),
+                class: failure_class(err),
+            }),
+            ..Report::new(command)
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// Retry module
+//
+// Bounds how long fetching the remote configuration may take. A policy starts
+// from the subcommand defaults and is then refined by the optional `retry`
+// object of os-config.json and finally by command line flags.
+
+use std::time::Duration;
+
+use crate::args::OsConfigSubcommand;
+use crate::random::random_duration;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A one-shot `update` waits this long for the network to come up
+const UPDATE_MAX_DURATION: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: Option<u32>,
+    pub max_duration: Option<Duration>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub connect_timeout: Duration,
+    // Bounds the whole request once connected
+    pub read_timeout: Duration,
+}
+
+// Partial policy as given in os-config.json or on the command line, in seconds
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_backoff: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_backoff: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<u64>,
+}
+
+impl RetryPolicy {
+    pub fn for_subcommand(subcommand: &OsConfigSubcommand) -> Self {
+        let policy = RetryPolicy {
+            max_attempts: Some(1),
+            max_duration: None,
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            connect_timeout: CONNECT_TIMEOUT,
+            read_timeout: READ_TIMEOUT,
+        };
+
+        // `join` fails fast so that a mistyped endpoint is reported right away, and the
+        // daemon simply checks again on its next interval
+        match subcommand {
+            OsConfigSubcommand::Update => RetryPolicy {
+                max_attempts: None,
+                max_duration: Some(UPDATE_MAX_DURATION),
+                ..policy
+            },
+            _ => policy,
+        }
+    }
+
+    pub fn with_settings(self, settings: &RetrySettings) -> Self {
+        RetryPolicy {
+            // Zero attempts or seconds stand for no limit
+            max_attempts: settings.max_attempts.map_or(self.max_attempts, |attempts| {
+                Some(attempts).filter(|a| *a != 0)
+            }),
+            max_duration: settings.max_duration.map_or(self.max_duration, |secs| {
+                Some(Duration::from_secs(secs)).filter(|d| !d.is_zero())
+            }),
+            initial_backoff: settings
+                .initial_backoff
+                .map_or(self.initial_backoff, Duration::from_secs),
+            max_backoff: settings
+                .max_backoff
+                .map_or(self.max_backoff, Duration::from_secs),
+            connect_timeout: settings
+                .connect_timeout
+                .map_or(self.connect_timeout, Duration::from_secs),
+            read_timeout: settings
+                .read_timeout
+                .map_or(self.read_timeout, Duration::from_secs),
+        }
+    }
+
+    // Exponential backoff with equal jitter: half of the delay is fixed, half is random
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+
+        backoff / 2 + random_duration(backoff / 2)
+    }
+
+    pub fn gives_up(&self, attempts: u32, elapsed: Duration) -> bool {
+        self.max_attempts
+            .map_or(false, |max_attempts| attempts >= max_attempts)
+            || self
+                .max_duration
+                .map_or(false, |max_duration| elapsed >= max_duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommand_defaults() {
+        let update = RetryPolicy::for_subcommand(&OsConfigSubcommand::Update);
+        assert_eq!(update.max_attempts, None);
+        assert_eq!(update.max_duration, Some(UPDATE_MAX_DURATION));
+
+        let join = RetryPolicy::for_subcommand(&OsConfigSubcommand::Join);
+        assert_eq!(join.max_attempts, Some(1));
+        assert!(join.gives_up(1, Duration::ZERO));
+    }
+
+    #[test]
+    fn settings_override_defaults() {
+        let policy = RetryPolicy::for_subcommand(&OsConfigSubcommand::Update).with_settings(
+            &RetrySettings {
+                max_attempts: Some(3),
+                max_duration: Some(0),
+                read_timeout: Some(5),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(policy.max_attempts, Some(3));
+        assert_eq!(policy.max_duration, None);
+        assert_eq!(policy.read_timeout, Duration::from_secs(5));
+        assert_eq!(policy.connect_timeout, CONNECT_TIMEOUT);
+
+        assert!(!policy.gives_up(2, Duration::from_secs(3600)));
+        assert!(policy.gives_up(3, Duration::ZERO));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = RetryPolicy::for_subcommand(&OsConfigSubcommand::Update);
+
+        let first = policy.backoff(1);
+        assert!(first >= INITIAL_BACKOFF / 2 && first <= INITIAL_BACKOFF);
+
+        let late = policy.backoff(100);
+        assert!(late >= MAX_BACKOFF / 2 && late <= MAX_BACKOFF);
+    }
+}
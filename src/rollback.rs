@@ -0,0 +1,126 @@
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+use crate::args::Args;
+use crate::cache::{clear_configuration, clear_validators, write_configuration};
+use crate::config_json::{read_config_json, ConfigMap};
+use crate::generation::{
+    current_generation, list_generations, read_generation, set_current_generation,
+};
+use crate::join::{apply, has_service_config_changes};
+use crate::pointer::{get_node, parse_pointer, remove_node, set_node};
+use crate::remote::Payload;
+use crate::report::Report;
+use crate::schema::{read_os_config_schema, OsConfigSchema};
+
+pub fn rollback(args: &Args) -> Result<Report> {
+    let mut report = Report::new("rollback");
+
+    let generations = list_generations(&args.state_dir)?;
+    let current = current_generation(&args.state_dir)?;
+
+    let target = if let Some(target) = args.rollback_to {
+        if !generations.contains(&target) {
+            bail!("Generation {} not found", target);
+        }
+        target
+    } else if let Some(previous) = generations
+        .iter()
+        .rev()
+        .find(|generation| Some(**generation) < current)
+    {
+        *previous
+    } else {
+        bail!("No previous generation to roll back to");
+    };
+
+    info!("Rolling back to generation {}...", target);
+
+    let generation = read_generation(&args.state_dir, target)?;
+
+    let schema = read_os_config_schema(&args.os_config_path)?;
+
+    let current_config_json = read_config_json(&args.config_json_path)?;
+
+    let config_json = restore_whitelisted(&schema, &generation.config_json, &current_config_json);
+
+    let has_service_config_changes = has_service_config_changes(&schema, &generation)?;
+
+    let should_write_config_json = current_config_json != config_json;
+
+    report.service_config_changes = has_service_config_changes;
+
+    if has_service_config_changes || should_write_config_json {
+        apply(
+            args,
+            &config_json,
+            &schema,
+            &generation,
+            has_service_config_changes,
+            should_write_config_json,
+            &mut report,
+        )?;
+    } else {
+        info!("No configuration changes");
+    }
+
+    set_current_generation(&args.state_dir, target)?;
+
+    // The device no longer holds the configuration the validators were issued for, so the
+    // next update has to fetch it in full to reapply it. Until then an offline update
+    // keeps the restored configuration. It is not signed, so with a signing key configured
+    // the offline update refuses it rather than trusting a locally rebuilt payload. A
+    // generation with files that did not exist is not cached at all, as a configuration
+    // cannot express that.
+    let cached =
+        clear_validators(&args.state_dir).and_then(|_| match generation.to_remote_config()? {
+            Some(remote_config) => write_configuration(
+                &args.state_dir,
+                &Payload {
+                    body: serde_json::to_string_pretty(&remote_config)?,
+                    signature: None,
+                },
+            ),
+            None => clear_configuration(&args.state_dir),
+        });
+
+    if let Err(err) = cached {
+        warn!("Caching restored configuration failed: {:#}", err);
+    }
+
+    report.generation = Some(target);
+
+    Ok(report)
+}
+
+// Only the keys os-config manages are rolled back, everything else written since, e.g. a
+// rotated API key or root CA, is kept as it is
+fn restore_whitelisted(
+    schema: &OsConfigSchema,
+    generation_config_json: &ConfigMap,
+    current_config_json: &ConfigMap,
+) -> ConfigMap {
+    let generation_config_json = Value::Object(generation_config_json.clone());
+    let mut restored = Value::Object(current_config_json.clone());
+
+    for key in &schema.config.whitelist {
+        let tokens = parse_pointer(key);
+
+        match get_node(&generation_config_json, &tokens) {
+            Some(value) => {
+                if let Err(err) = set_node(&mut restored, &tokens, value.clone()) {
+                    warn!("Restoring key `{}` failed: {:#}", key, err);
+                }
+            }
+            None => {
+                remove_node(&mut restored, &tokens);
+            }
+        }
+    }
+
+    let Value::Object(restored) = restored else {
+        unreachable!()
+    };
+
+    restored
+}
@@ -1,4 +1,6 @@
+use crate::exit::Failure;
 use crate::fs::read_file;
+use crate::retry::RetrySettings;
 use anyhow::{Context, Result};
 use std::collections::HashMap;
 use std::path::Path;
@@ -9,6 +11,12 @@ pub struct OsConfigSchema {
     // Fields that should be removed from config.json when leaving a cloud env (`balena leave`)
     pub keys: Vec<String>,
     pub config: ConfigJsonSchema,
+    // Optional limits for fetching the remote configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetrySettings>,
+    // PEM encoded public key the remote configuration has to be signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -30,8 +38,17 @@ pub struct ConfigJsonSchema {
     pub whitelist: Vec<String>,
 }
 
+// Config files of a service alphanumerically, for integration testing consistency
+pub fn sorted_files(service: &Service) -> impl Iterator<Item = (&String, &ConfigFile)> {
+    let mut files = service.files.iter().collect::<Vec<_>>();
+    files.sort_by_key(|(name, _)| *name);
+    files.into_iter()
+}
+
 pub fn read_os_config_schema(os_config_path: &Path) -> Result<OsConfigSchema> {
-    read_os_config_schema_impl(os_config_path).context("Reading `os-config.json` schema failed")
+    read_os_config_schema_impl(os_config_path).context(Failure::Schema(
+        "Reading `os-config.json` schema failed".into(),
+    ))
 }
 
 fn read_os_config_schema_impl(os_config_path: &Path) -> Result<OsConfigSchema> {
@@ -116,6 +133,8 @@ mod tests {
             config: ConfigJsonSchema {
                 whitelist: vec!["logsEndpoint".into()],
             },
+            retry: None,
+            signing_key: None,
         };
 
         assert_eq!(parsed, expected);
@@ -0,0 +1,66 @@
+// Signal module
+//
+// The daemon only needs to know that a signal arrived, so the handlers just
+// raise flags that the polling loop checks between runs. Termination is also
+// checked between fetch retries and before a configuration is applied.
+
+use std::cmp::min;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+// How often the terminate flag is checked while sleeping
+const SLEEP_STEP: Duration = Duration::from_millis(250);
+
+static RECHECK: AtomicBool = AtomicBool::new(false);
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    if signal == libc::SIGHUP {
+        RECHECK.store(true, Ordering::SeqCst);
+    } else {
+        TERMINATE.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn install_handlers() -> Result<()> {
+    for signal in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT] {
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error())
+                .context(format!("Installing handler for signal {signal} failed"));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn take_recheck() -> bool {
+    RECHECK.swap(false, Ordering::SeqCst)
+}
+
+pub fn should_terminate() -> bool {
+    TERMINATE.load(Ordering::SeqCst)
+}
+
+// Returns `false` if termination was requested before the duration elapsed
+pub fn sleep_unless_terminated(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+
+    loop {
+        if should_terminate() {
+            return false;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+
+        thread::sleep(min(SLEEP_STEP, deadline - now));
+    }
+}
@@ -0,0 +1,81 @@
+// Signature module
+//
+// Verifies the detached signature the API sends along with the remote
+// configuration. The signature is the base64 encoded signature of the raw
+// response body, SHA-256 for RSA and EC keys and pure Ed25519 for Ed25519 keys.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+
+use anyhow::{bail, Context, Result};
+
+pub type SigningKey = PKey<Public>;
+
+pub fn parse_signing_key(pem: &[u8]) -> Result<SigningKey> {
+    PKey::public_key_from_pem(pem).context("Not a valid PEM encoded public key")
+}
+
+pub fn verify_signature(key: &SigningKey, data: &[u8], signature: &str) -> Result<()> {
+    let signature = STANDARD
+        .decode(signature.trim())
+        .context("Signature base64 decoding failed")?;
+
+    let mut verifier = if key.id() == Id::ED25519 {
+        Verifier::new_without_digest(key)?
+    } else {
+        Verifier::new(MessageDigest::sha256(), key)?
+    };
+
+    // Malformed signatures make some key types error out instead of just not matching
+    if !verifier.verify_oneshot(&signature, data).unwrap_or(false) {
+        bail!("Signature does not match");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+
+    fn generate_key() -> (PKey<Private>, SigningKey) {
+        let private_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let public_key = parse_signing_key(&private_key.public_key_to_pem().unwrap()).unwrap();
+        (private_key, public_key)
+    }
+
+    fn sign(private_key: &PKey<Private>, data: &[u8]) -> String {
+        let mut signer = Signer::new(MessageDigest::sha256(), private_key).unwrap();
+        STANDARD.encode(signer.sign_oneshot_to_vec(data).unwrap())
+    }
+
+    #[test]
+    fn verify_valid_signature() {
+        let (private_key, public_key) = generate_key();
+        let signature = sign(&private_key, b"configuration");
+        verify_signature(&public_key, b"configuration", &signature).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Signature does not match")]
+    fn reject_signature_of_other_data() {
+        let (private_key, public_key) = generate_key();
+        let signature = sign(&private_key, b"configuration");
+        verify_signature(&public_key, b"tampered configuration", &signature).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Signature base64 decoding failed")]
+    fn reject_malformed_signature() {
+        let (_private_key, public_key) = generate_key();
+        verify_signature(&public_key, b"configuration", "not base64!").unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use crate::fs;
+use std::path::Path;
+
+use crate::args::Args;
+use crate::config_json::{
+    get_api_endpoint, get_api_key, get_stored_api_keys, read_config_json, redact_api_key,
+};
+use crate::report::Report;
+use crate::schema::{read_os_config_schema, sorted_files};
+use anyhow::Result;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceStatus {
+    pub managed: bool,
+    pub api_endpoint: Option<String>,
+    pub device_api_key: Option<String>,
+    pub stored_api_keys: Vec<StoredApiKey>,
+    pub services: Vec<ServiceStatus>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredApiKey {
+    pub api_endpoint: String,
+    pub api_key: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceStatus {
+    pub id: String,
+    pub files: Vec<ConfigFileStatus>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigFileStatus {
+    pub name: String,
+    pub path: String,
+    pub exists: bool,
+    pub mode: Option<String>,
+}
+
+pub fn status(args: &Args) -> Result<Report> {
+    let mut report = Report::new("status");
+
+    let status = get_device_status(args)?;
+
+    if let Some(ref api_endpoint) = status.api_endpoint {
+        info!("Managed device");
+        info!("API endpoint: {}", api_endpoint);
+    } else {
+        info!("Unconfigured device");
+    }
+
+    if let Some(ref api_key) = status.device_api_key {
+        info!("deviceApiKey: {}", api_key);
+    }
+
+    if status.stored_api_keys.is_empty() {
+        info!("No stored deviceApiKeys");
+    } else {
+        info!("Stored deviceApiKeys:");
+        for stored in &status.stored_api_keys {
+            info!("    {}: {}", stored.api_endpoint, stored.api_key);
+        }
+    }
+
+    for service in &status.services {
+        info!("Service {}:", service.id);
+
+        for file in &service.files {
+            if let Some(ref mode) = file.mode {
+                info!("    {}: {} (mode {})", file.name, file.path, mode);
+            } else {
+                info!("    {}: {} (missing)", file.name, file.path);
+            }
+        }
+    }
+
+    report.unconfigured = !status.managed;
+    report.status = Some(status);
+
+    Ok(report)
+}
+
+fn get_device_status(args: &Args) -> Result<DeviceStatus> {
+    let config_json = read_config_json(&args.config_json_path)?;
+
+    let api_endpoint = get_api_endpoint(&config_json)?;
+
+    let device_api_key = get_api_key(&config_json)?.map(|api_key| redact_api_key(&api_key));
+
+    let stored_api_keys = get_stored_api_keys(&config_json)?
+        .into_iter()
+        .map(|(api_endpoint, api_key)| StoredApiKey {
+            api_endpoint,
+            api_key: redact_api_key(&api_key),
+        })
+        .collect();
+
+    let schema = read_os_config_schema(&args.os_config_path)?;
+
+    let mut services = Vec::new();
+
+    for service in &schema.services {
+        let mut files = Vec::new();
+
+        for (name, config_file) in sorted_files(service) {
+            let mode = fs::get_mode(Path::new(&config_file.path))
+                .ok()
+                .map(|mode| format!("{mode:o}"));
+
+            files.push(ConfigFileStatus {
+                name: name.clone(),
+                path: config_file.path.clone(),
+                exists: mode.is_some(),
+                mode,
+            });
+        }
+
+        services.push(ServiceStatus {
+            id: service.id.clone(),
+            files,
+        });
+    }
+
+    Ok(DeviceStatus {
+        managed: api_endpoint.is_some(),
+        api_endpoint,
+        device_api_key,
+        stored_api_keys,
+        services,
+    })
+}
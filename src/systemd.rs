@@ -1,4 +1,5 @@
 use std::env;
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 
@@ -8,27 +9,32 @@ use zbus::blocking::Connection;
 use zbus::dbus_proxy;
 use zbus::zvariant::OwnedObjectPath;
 
+use crate::exit::Failure;
+
 const DEFAULT_MODE: &str = "replace";
 
 const MOCK_SYSTEMD: &str = "MOCK_SYSTEMD";
 
+// Shared between calls, so that the daemon keeps a single system bus connection
+static CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+
 pub fn start_service(name: &str) -> Result<()> {
     info!("Starting {name}...");
 
     if should_mock_systemd() {
         return Ok(());
     }
-    start_service_impl(name).context(format!("Starting {name} failed"))
+    start_service_impl(name).context(Failure::Systemd(format!("Starting {name} failed")))
 }
 
 fn start_service_impl(name: &str) -> Result<()> {
-    let connection = Connection::system()?;
-
-    let manager = ManagerProxyBlocking::new(&connection)?;
+    with_connection(|connection| {
+        let manager = ManagerProxyBlocking::new(connection)?;
 
-    manager.start_unit(name, DEFAULT_MODE)?;
+        manager.start_unit(name, DEFAULT_MODE)?;
 
-    Ok(())
+        Ok(())
+    })
 }
 
 pub fn stop_service(name: &str) -> Result<()> {
@@ -37,17 +43,17 @@ pub fn stop_service(name: &str) -> Result<()> {
     if should_mock_systemd() {
         return Ok(());
     }
-    stop_service_impl(name).context(format!("Stopping {name} failed"))
+    stop_service_impl(name).context(Failure::Systemd(format!("Stopping {name} failed")))
 }
 
 fn stop_service_impl(name: &str) -> Result<()> {
-    let connection = Connection::system()?;
-
-    let manager = ManagerProxyBlocking::new(&connection)?;
+    with_connection(|connection| {
+        let manager = ManagerProxyBlocking::new(connection)?;
 
-    manager.stop_unit(name, DEFAULT_MODE)?;
+        manager.stop_unit(name, DEFAULT_MODE)?;
 
-    Ok(())
+        Ok(())
+    })
 }
 
 pub fn reload_or_restart_service(name: &str) -> Result<()> {
@@ -56,17 +62,19 @@ pub fn reload_or_restart_service(name: &str) -> Result<()> {
     if should_mock_systemd() {
         return Ok(());
     }
-    reload_or_restart_service_impl(name).context(format!("Reloading or restarting {name} failed"))
+    reload_or_restart_service_impl(name).context(Failure::Systemd(format!(
+        "Reloading or restarting {name} failed"
+    )))
 }
 
 fn reload_or_restart_service_impl(name: &str) -> Result<()> {
-    let connection = Connection::system()?;
+    with_connection(|connection| {
+        let manager = ManagerProxyBlocking::new(connection)?;
 
-    let manager = ManagerProxyBlocking::new(&connection)?;
+        manager.reload_or_restart_unit(name, DEFAULT_MODE)?;
 
-    manager.reload_or_restart_unit(name, DEFAULT_MODE)?;
-
-    Ok(())
+        Ok(())
+    })
 }
 
 pub fn await_service_exit(name: &str) -> Result<()> {
@@ -75,30 +83,31 @@ pub fn await_service_exit(name: &str) -> Result<()> {
     if should_mock_systemd() {
         return Ok(());
     }
-    await_service_exit_impl(name).context(format!("Awaiting {name} to exit failed"))
+    await_service_exit_impl(name)
+        .context(Failure::Systemd(format!("Awaiting {name} to exit failed")))
 }
 
 fn await_service_exit_impl(name: &str) -> Result<()> {
-    let connection = Connection::system()?;
+    with_connection(|connection| {
+        let manager = ManagerProxyBlocking::new(connection)?;
 
-    let manager = ManagerProxyBlocking::new(&connection)?;
+        let unit_path = manager.get_unit(name)?;
+        let unit = UnitProxyBlocking::builder(connection)
+            .path(&unit_path)?
+            .build()?;
 
-    let unit_path = manager.get_unit(name)?;
-    let unit = UnitProxyBlocking::builder(&connection)
-        .path(&unit_path)?
-        .build()?;
+        for _ in 0..90 {
+            let active_state = unit.active_state()?;
 
-    for _ in 0..90 {
-        let active_state = unit.active_state()?;
+            if active_state == "inactive" || active_state == "failed" {
+                return Ok(());
+            }
 
-        if active_state == "inactive" || active_state == "failed" {
-            return Ok(());
+            thread::sleep(Duration::from_secs(1));
         }
 
-        thread::sleep(Duration::from_secs(1));
-    }
-
-    bail!("Timed out awaiting service to exit")
+        bail!("Timed out awaiting service to exit")
+    })
 }
 
 pub fn service_exists(name: &str) -> bool {
@@ -109,14 +118,41 @@ pub fn service_exists(name: &str) -> bool {
 }
 
 fn service_exists_impl(name: &str) -> Result<bool> {
-    let connection = Connection::system()?;
+    with_connection(|connection| {
+        let manager = ManagerProxyBlocking::new(connection)?;
 
-    let manager = ManagerProxyBlocking::new(&connection)?;
+        match manager.get_unit(name) {
+            Ok(_) => Ok(true),
+            Err(_) => Ok(false),
+        }
+    })
+}
+
+fn with_connection<T>(f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+    let connection = system_connection()?;
+
+    let result = f(&connection);
+
+    // Reconnect on the next call in case the bus connection went away
+    if result.is_err() {
+        *CONNECTION.lock().unwrap_or_else(|err| err.into_inner()) = None;
+    }
+
+    result
+}
+
+fn system_connection() -> Result<Connection> {
+    let mut cached = CONNECTION.lock().unwrap_or_else(|err| err.into_inner());
 
-    match manager.get_unit(name) {
-        Ok(_) => Ok(true),
-        Err(_) => Ok(false),
+    if let Some(ref connection) = *cached {
+        return Ok(connection.clone());
     }
+
+    let connection = Connection::system()?;
+
+    *cached = Some(connection.clone());
+
+    Ok(connection)
 }
 
 fn should_mock_systemd() -> bool {
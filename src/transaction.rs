@@ -0,0 +1,105 @@
+// Transaction module
+//
+// Snapshots every file an apply is going to touch, so that a failure half way
+// through can put config.json and the service configuration files back the way
+// they were and restart the units that were stopped for the apply. Units that
+// were already started again with the new files are restarted as well, so that
+// they pick up the restored ones.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::fs;
+use crate::systemd;
+
+pub struct Transaction {
+    snapshots: Vec<FileSnapshot>,
+    stopped_units: Vec<String>,
+}
+
+struct FileSnapshot {
+    path: PathBuf,
+    // `None` when the file did not exist before the apply
+    original: Option<(Vec<u8>, u32)>,
+}
+
+impl Transaction {
+    pub fn begin(paths: &[&Path]) -> Result<Self> {
+        let mut snapshots = Vec::new();
+
+        for path in paths {
+            let original = if path.exists() {
+                Some((fs::read_file_bytes(path)?, fs::get_mode(path)?))
+            } else {
+                None
+            };
+
+            snapshots.push(FileSnapshot {
+                path: path.to_path_buf(),
+                original,
+            });
+        }
+
+        Ok(Transaction {
+            snapshots,
+            stopped_units: Vec::new(),
+        })
+    }
+
+    pub fn unit_stopped(&mut self, unit: &str) {
+        if !self.stopped_units.iter().any(|stopped| stopped == unit) {
+            self.stopped_units.push(unit.into());
+        }
+    }
+
+    pub fn rollback(&self) -> Result<()> {
+        info!("Rolling back configuration changes...");
+
+        let mut failures = 0;
+
+        for snapshot in &self.snapshots {
+            if let Err(err) = snapshot.restore() {
+                error!("Restoring {:?} failed: {:#}", snapshot.path, err);
+                failures += 1;
+            }
+        }
+
+        for unit in &self.stopped_units {
+            if let Err(err) = systemd::reload_or_restart_service(unit) {
+                error!("{:#}", err);
+                failures += 1;
+            }
+        }
+
+        if failures == 0 {
+            Ok(())
+        } else {
+            Err(anyhow!("Rollback failed in {} step(s)", failures))
+        }
+    }
+}
+
+impl FileSnapshot {
+    fn restore(&self) -> Result<()> {
+        match self.original {
+            Some((ref contents, mode)) => {
+                let current = fs::read_file_bytes(&self.path).ok();
+                let current_mode = fs::get_mode(&self.path).ok();
+
+                if current.as_ref() != Some(contents) || current_mode != Some(mode) {
+                    fs::write_file_bytes(&self.path, contents, Some(mode))?;
+                    info!("{} restored", self.path.display());
+                }
+            }
+            None => {
+                if self.path.exists() {
+                    fs::remove_file(&self.path)?;
+                    info!("{} removed", self.path.display());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
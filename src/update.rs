@@ -4,9 +4,13 @@ use crate::args::Args;
 
 use crate::config_json::read_config_json;
 use crate::join::reconfigure;
+use crate::report::Report;
+use crate::schema::read_os_config_schema;
 
-pub fn update(args: &Args) -> Result<()> {
+pub fn update(args: &Args) -> Result<Report> {
     let mut config_json = read_config_json(&args.config_json_path)?;
 
-    reconfigure(args, &mut config_json, false)
+    let schema = read_os_config_schema(&args.os_config_path)?;
+
+    reconfigure(args, &schema, &mut config_json, false)
 }
@@ -0,0 +1,428 @@
+// Validate module
+//
+// Lints an os-config.json schema before it ships in an image. The schema is
+// walked as a plain JSON value instead of being deserialized, so that every
+// problem is reported with its location rather than just the first serde
+// error.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+
+use crate::args::Args;
+use crate::exit::Failure;
+use crate::fs::{parse_mode, read_file};
+use crate::pointer::parse_pointer;
+use crate::report::Report;
+use crate::signature::parse_signing_key;
+
+const SCHEMA_FIELDS: &[&str] = &["services", "keys", "config"];
+const OPTIONAL_SCHEMA_FIELDS: &[&str] = &["retry", "signing_key"];
+const RETRY_FIELDS: &[&str] = &[
+    "max_attempts",
+    "max_duration",
+    "initial_backoff",
+    "max_backoff",
+    "connect_timeout",
+    "read_timeout",
+];
+const SERVICE_FIELDS: &[&str] = &["id", "files", "systemd_services"];
+const CONFIG_FILE_FIELDS: &[&str] = &["path", "perm"];
+const CONFIG_FIELDS: &[&str] = &["whitelist"];
+
+const UNIT_SUFFIXES: &[&str] = &[
+    ".service",
+    ".socket",
+    ".device",
+    ".mount",
+    ".automount",
+    ".swap",
+    ".target",
+    ".path",
+    ".timer",
+    ".slice",
+    ".scope",
+];
+
+pub fn validate(args: &Args) -> Result<Report> {
+    let mut report = Report::new("validate");
+
+    let path = &args.os_config_path;
+
+    let json_data = read_file(path)?;
+
+    let schema: Value = serde_json::from_str(&json_data)
+        .context(Failure::Schema(format!("Parsing {path:?} failed")))?;
+
+    let problems = validate_schema(&schema);
+
+    if problems.is_empty() {
+        info!("{} is valid", path.display());
+    } else {
+        info!("{} problem(s) found in {}:", problems.len(), path.display());
+        for problem in &problems {
+            info!("    {}", problem);
+        }
+    }
+
+    report.problems = problems;
+
+    Ok(report)
+}
+
+pub fn validate_schema(schema: &Value) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let Some(schema) = expect_object(schema, "schema", &mut problems) else {
+        return problems;
+    };
+
+    check_fields(
+        schema,
+        SCHEMA_FIELDS,
+        OPTIONAL_SCHEMA_FIELDS,
+        "",
+        &mut problems,
+    );
+
+    let keys = schema
+        .get("keys")
+        .map(|keys| validate_strings(keys, "keys", &mut problems))
+        .unwrap_or_default();
+
+    if let Some(services) = schema.get("services") {
+        validate_services(services, &mut problems);
+    }
+
+    if let Some(config) = schema.get("config") {
+        if let Some(config) = expect_object(config, "config", &mut problems) {
+            check_fields(config, CONFIG_FIELDS, &[], "config.", &mut problems);
+
+            if let Some(whitelist) = config.get("whitelist") {
+                let whitelist = validate_strings(whitelist, "config.whitelist", &mut problems);
+                for (index, key) in whitelist.iter().enumerate() {
+                    // Pointers are checked by the top-level key they point into
+                    if keys.contains(&parse_pointer(key)[0].as_str()) {
+                        problems.push(format!(
+                            "config.whitelist[{index}]: `{key}` is also listed in `keys`"
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(retry) = schema.get("retry") {
+        if let Some(retry) = expect_object(retry, "retry", &mut problems) {
+            check_fields(retry, &[], RETRY_FIELDS, "retry.", &mut problems);
+
+            for (field, value) in retry {
+                if !value.is_u64() {
+                    problems.push(format!("retry.{field}: expected a non-negative integer"));
+                }
+            }
+        }
+    }
+
+    if let Some(signing_key) = schema.get("signing_key") {
+        if let Some(signing_key) = expect_str(signing_key, "signing_key", &mut problems) {
+            if let Err(err) = parse_signing_key(signing_key.as_bytes()) {
+                problems.push(format!("signing_key: {err}"));
+            }
+        }
+    }
+
+    problems
+}
+
+fn validate_services(services: &Value, problems: &mut Vec<String>) {
+    let Some(services) = expect_array(services, "services", problems) else {
+        return;
+    };
+
+    let mut ids = HashMap::new();
+    let mut paths = HashMap::new();
+
+    for (index, service) in services.iter().enumerate() {
+        let location = format!("services[{index}]");
+
+        let Some(service) = expect_object(service, &location, problems) else {
+            continue;
+        };
+
+        check_fields(
+            service,
+            SERVICE_FIELDS,
+            &[],
+            &format!("{location}."),
+            problems,
+        );
+
+        if let Some(id) = service.get("id") {
+            let id_location = format!("{location}.id");
+            if let Some(id) = expect_str(id, &id_location, problems) {
+                if let Some(first) = ids.insert(id, id_location.clone()) {
+                    problems.push(format!(
+                        "{id_location}: duplicate service id `{id}`, first used in {first}"
+                    ));
+                }
+            }
+        }
+
+        if let Some(files) = service.get("files") {
+            validate_files(files, &format!("{location}.files"), &mut paths, problems);
+        }
+
+        if let Some(units) = service.get("systemd_services") {
+            let units_location = format!("{location}.systemd_services");
+            let units = validate_strings(units, &units_location, problems);
+            for (index, unit) in units.iter().enumerate() {
+                if !UNIT_SUFFIXES.iter().any(|suffix| unit.ends_with(suffix)) {
+                    problems.push(format!(
+                        "{units_location}[{index}]: `{unit}` has no systemd unit suffix"
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn validate_files<'a>(
+    files: &'a Value,
+    location: &str,
+    paths: &mut HashMap<&'a str, String>,
+    problems: &mut Vec<String>,
+) {
+    let Some(files) = expect_object(files, location, problems) else {
+        return;
+    };
+
+    // Iterate through config files alphanumerically for stable reports
+    let mut names = files.keys().collect::<Vec<_>>();
+    names.sort();
+
+    for name in names {
+        let file_location = format!("{location}.{name}");
+
+        let Some(file) = expect_object(&files[name], &file_location, problems) else {
+            continue;
+        };
+
+        check_fields(
+            file,
+            CONFIG_FILE_FIELDS,
+            &[],
+            &format!("{file_location}."),
+            problems,
+        );
+
+        if let Some(path) = file.get("path") {
+            let path_location = format!("{file_location}.path");
+            if let Some(path) = expect_str(path, &path_location, problems) {
+                if !path.starts_with('/') {
+                    problems.push(format!("{path_location}: `{path}` is not an absolute path"));
+                }
+
+                if let Some(first) = paths.insert(path, path_location.clone()) {
+                    problems.push(format!(
+                        "{path_location}: duplicate file path `{path}`, first used in {first}"
+                    ));
+                }
+            }
+        }
+
+        if let Some(perm) = file.get("perm") {
+            let perm_location = format!("{file_location}.perm");
+            if let Some(perm) = expect_str(perm, &perm_location, problems) {
+                if !is_valid_perm(perm) {
+                    problems.push(format!(
+                        "{perm_location}: `{perm}` is not an octal permission mode"
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn is_valid_perm(perm: &str) -> bool {
+    match parse_mode(perm) {
+        Ok(Some(mode)) => mode <= 0o7777,
+        Ok(None) => true,
+        Err(_) => false,
+    }
+}
+
+fn check_fields(
+    object: &Map<String, Value>,
+    required: &[&str],
+    optional: &[&str],
+    prefix: &str,
+    problems: &mut Vec<String>,
+) {
+    for field in required {
+        if !object.contains_key(*field) {
+            problems.push(format!("{prefix}{field}: missing field"));
+        }
+    }
+
+    for field in object.keys() {
+        if !required.contains(&(field as &str)) && !optional.contains(&(field as &str)) {
+            problems.push(format!("{prefix}{field}: unknown field"));
+        }
+    }
+}
+
+fn validate_strings<'a>(
+    value: &'a Value,
+    location: &str,
+    problems: &mut Vec<String>,
+) -> Vec<&'a str> {
+    let Some(array) = expect_array(value, location, problems) else {
+        return vec![];
+    };
+
+    array
+        .iter()
+        .enumerate()
+        .filter_map(|(index, item)| expect_str(item, &format!("{location}[{index}]"), problems))
+        .collect()
+}
+
+fn expect_object<'a>(
+    value: &'a Value,
+    location: &str,
+    problems: &mut Vec<String>,
+) -> Option<&'a Map<String, Value>> {
+    let object = value.as_object();
+    if object.is_none() {
+        problems.push(format!("{location}: expected an object"));
+    }
+    object
+}
+
+fn expect_array<'a>(
+    value: &'a Value,
+    location: &str,
+    problems: &mut Vec<String>,
+) -> Option<&'a Vec<Value>> {
+    let array = value.as_array();
+    if array.is_none() {
+        problems.push(format!("{location}: expected an array"));
+    }
+    array
+}
+
+fn expect_str<'a>(value: &'a Value, location: &str, problems: &mut Vec<String>) -> Option<&'a str> {
+    let string = value.as_str();
+    if string.is_none() {
+        problems.push(format!("{location}: expected a string"));
+    }
+    string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_schema() {
+        let schema = json!({
+            "services": [
+                {
+                    "id": "openvpn",
+                    "files": {
+                        "config": {
+                            "path": "/etc/openvpn/openvpn.conf",
+                            "perm": ""
+                        },
+                        "ca": {
+                            "path": "/etc/openvpn/ca.crt",
+                            "perm": "600"
+                        }
+                    },
+                    "systemd_services": ["openvpn.service"]
+                }
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        });
+
+        assert!(validate_schema(&schema).is_empty());
+    }
+
+    #[test]
+    fn invalid_schema() {
+        let schema = json!({
+            "services": [
+                {
+                    "id": "openvpn",
+                    "files": {
+                        "config": {
+                            "path": "etc/openvpn/openvpn.conf",
+                            "perm": "rw"
+                        },
+                        "ca": {
+                            "path": "/etc/openvpn/ca.crt",
+                            "perm": "600",
+                            "owner": "root"
+                        }
+                    },
+                    "systemd_services": ["openvpn"]
+                },
+                {
+                    "id": "openvpn",
+                    "files": {
+                        "ca": {
+                            "path": "/etc/openvpn/ca.crt",
+                            "perm": "0999"
+                        }
+                    }
+                }
+            ],
+            "keys": ["apiKey", "apiEndpoint", "logsEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        });
+
+        assert_eq!(
+            validate_schema(&schema),
+            vec![
+                "services[0].files.ca.owner: unknown field",
+                "services[0].files.config.path: `etc/openvpn/openvpn.conf` is not an absolute path",
+                "services[0].files.config.perm: `rw` is not an octal permission mode",
+                "services[0].systemd_services[0]: `openvpn` has no systemd unit suffix",
+                "services[1].systemd_services: missing field",
+                "services[1].id: duplicate service id `openvpn`, first used in services[0].id",
+                "services[1].files.ca.path: duplicate file path `/etc/openvpn/ca.crt`, first used in services[0].files.ca.path",
+                "services[1].files.ca.perm: `0999` is not an octal permission mode",
+                "config.whitelist[0]: `logsEndpoint` is also listed in `keys`",
+            ]
+        );
+    }
+
+    #[test]
+    fn schema_with_wrong_types() {
+        let schema = json!({
+            "services": {},
+            "keys": "apiKey",
+            "config": {
+                "whitelist": [1]
+            },
+            "extra": true
+        });
+
+        assert_eq!(
+            validate_schema(&schema),
+            vec![
+                "extra: unknown field",
+                "keys: expected an array",
+                "services: expected an array",
+                "config.whitelist[0]: expected a string",
+            ]
+        );
+    }
+}
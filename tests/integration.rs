@@ -1,3 +1,5 @@
+use std::fs::remove_file;
+use std::io::{BufRead, BufReader};
 use std::time::Duration;
 
 use assert_cmd::Command;
@@ -10,6 +12,9 @@ const OS_CONFIG_PATH_REDEFINE: &str = "OS_CONFIG_PATH_REDEFINE";
 const CONFIG_JSON_PATH_REDEFINE: &str = "CONFIG_JSON_PATH_REDEFINE";
 const CONFIG_JSON_FLASHER_PATH_REDEFINE: &str = "CONFIG_JSON_FLASHER_PATH_REDEFINE";
 const FLASHER_FLAG_PATH_REDEFINE: &str = "FLASHER_FLAG_PATH_REDEFINE";
+const OS_CONFIG_TOML_PATH_REDEFINE: &str = "OS_CONFIG_TOML_PATH_REDEFINE";
+const STATE_DIR_REDEFINE: &str = "STATE_DIR_REDEFINE";
+const BOOT_DIR_REDEFINE: &str = "BOOT_DIR_REDEFINE";
 
 const MOCK_SYSTEMD: &str = "MOCK_SYSTEMD";
 
@@ -2054,21 +2059,3281 @@ fn ignore_unknown_cloud_config_fields() {
 
     serve.stop();
 }
+
+#[test]
+fn status() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "https://api.balena-cloud.com",
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceApiKeys": {
+                "api.balena-cloud.com": "f0f0236b70be9a5983d3fd49ac9719b9",
+                "api.balenadev.io": "2fbe57c1d5c34e5f8fd0e9ff0a3bbb0c"
+            }
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    create_tmp_file(&tmp_dir, "mock-1.conf", "MOCK-1", Some(0o600));
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1-2",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }},
+                        "mock-2": {{
+                            "path": "{tmp_dir_path}/mock-2.conf",
+                            "perm": "755"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service", "mock-service-2.service"]
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Managed device
+        API endpoint: https://api.balena-cloud.com
+        deviceApiKey: f0f0236...
+        Stored deviceApiKeys:
+            api.balena-cloud.com: f0f0236...
+            api.balenadev.io: 2fbe57c...
+        Service mock-1-2:
+            mock-1: {tmp_dir_path}/mock-1.conf (mode 600)
+            mock-2: {tmp_dir_path}/mock-2.conf (missing)
+        "#
+    ));
+
+    get_base_command()
+        .args(["status"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_json_file(&config_json_path, config_json, false);
+}
+
+#[test]
+#[timeout(10000)]
+fn update_dry_run() {
+    let port = 31016;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}",
+            "vpnEndpoint": "vpn.resin.io",
+            "registryEndpoint": "registry2.resin.io",
+            "deltaEndpoint": "https://delta.resin.io",
+            "version": "9.99.9+rev1.prod"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1-2",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }},
+                        "mock-2": {{
+                            "path": "{tmp_dir_path}/mock-2.conf",
+                            "perm": "755"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service", "mock-service-2.service"]
+
+                }},
+                {{
+                    "id": "mock-3",
+                    "files": {{
+                        "mock-3": {{
+                            "path": "{tmp_dir_path}/mock-3.conf",
+                            "perm": ""
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-3.service"]
+
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    create_tmp_file(&tmp_dir, "mock-2.conf", "MOCK-2-0123456789", None);
+
+    create_tmp_file(&tmp_dir, "mock-3.conf", "MOCK-3-0000000000", None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1-2": {
+                    "mock-1": "MOCK-1-АБВГДЕЖЗИЙ",
+                    "mock-2": "MOCK-2-0123456789"
+                },
+                "mock-3": {
+                    "mock-3": "MOCK-3-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {
+                    "logsEndpoint": "https://logs.balenadev.io"
+                }
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Key `logsEndpoint` not found, will insert `"https://logs.balenadev.io"`
+        Done config.json migrations
+        Dry run, the following changes would be applied:
+        Would stop balena-supervisor.service if present
+        Would write {tmp_dir_path}/config.json
+        Would stop mock-service-1.service
+        Would stop mock-service-2.service
+        Would update {tmp_dir_path}/mock-1.conf
+        Would start mock-service-1.service
+        Would start mock-service-2.service
+        Would stop mock-service-3.service
+        Would update {tmp_dir_path}/mock-3.conf
+        Would start mock-service-3.service
+        Would start balena-supervisor.service if present
+        "#
+    ));
+
+    get_base_command()
+        .args(["update", "--dry-run"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_does_not_exist(&format!("{tmp_dir_path}/mock-1.conf"));
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-3.conf"),
+        "MOCK-3-0000000000",
+        None,
+    );
+
+    validate_json_file(&config_json_path, &config_json, false);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn join_dry_run() {
+    let port = 31017;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let json_config = format!(
+        r#"
+        {{
+            "deviceType": "raspberrypi3",
+            "apiEndpoint": "http://{}",
+            "vpnEndpoint": "vpn.resin.io"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        No configuration changes
+        Dry run, the following changes would be applied:
+        Would stop balena-supervisor.service if present
+        Would write {tmp_dir_path}/config.json
+        Would start balena-supervisor.service if present
+        "#
+    ));
+
+    get_base_command()
+        .args(["join", "--dry-run", &json_config])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_json_file(&config_json_path, config_json, false);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_diff() {
+    let port = 31018;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "apiEndpoint": "http://{}",
+            "logsEndpoint": "https://logs.resin.io"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": ""
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service"]
+
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    create_tmp_file(&tmp_dir, "mock-1.conf", "port 443\nproto tcp", None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "port 1194\nproto tcp"
+                }
+            },
+            "config": {
+                "overrides": {
+                    "logsEndpoint": "https://logs.balenadev.io"
+                }
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Key `logsEndpoint` found with existing value `"https://logs.resin.io"`, will override to `"https://logs.balenadev.io"`
+        Done config.json migrations
+        --- {tmp_dir_path}/mock-1.conf
+        +++ {tmp_dir_path}/mock-1.conf
+        @@ -1,2 +1,2 @@
+        -port 443
+        +port 1194
+         proto tcp
+        \ No newline at end of file
+        --- {tmp_dir_path}/config.json
+        +++ {tmp_dir_path}/config.json
+        -logsEndpoint: "https://logs.resin.io"
+        +logsEndpoint: "https://logs.balenadev.io"
+        Dry run, the following changes would be applied:
+        Would stop balena-supervisor.service if present
+        Would write {tmp_dir_path}/config.json
+        Would stop mock-service-1.service
+        Would update {tmp_dir_path}/mock-1.conf
+        Would start mock-service-1.service
+        Would start balena-supervisor.service if present
+        "#
+    ));
+
+    get_base_command()
+        .args(["update", "--dry-run", "--diff"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_json_file(&config_json_path, &config_json, false);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_json_output() {
+    let port = 31019;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": ""
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service"]
+
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {
+                    "logsEndpoint": "https://logs.balenadev.io"
+                }
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Key `logsEndpoint` not found, will insert `"https://logs.balenadev.io"`
+        Done config.json migrations
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Writing {tmp_dir_path}/config.json
+        Stopping mock-service-1.service...
+        Awaiting mock-service-1.service to exit...
+        {tmp_dir_path}/mock-1.conf updated
+        Starting mock-service-1.service...
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    let assert = get_base_command()
+        .args(["--output", "json", "update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stderr(output);
+
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+
+    let expected: serde_json::Value = serde_json::from_str(&format!(
+        r#"
+        {{
+            "command": "update",
+            "unconfigured": false,
+            "dryRun": false,
+            "cached": false,
+            "serviceConfigChanges": true,
+            "writtenFiles": ["{tmp_dir_path}/config.json", "{tmp_dir_path}/mock-1.conf"],
+            "deletedFiles": [],
+            "migratedKeys": ["logsEndpoint"],
+            "restartedUnits": ["mock-service-1.service", "balena-supervisor.service"],
+            "generation": 2
+        }}
+        "#
+    ))
+    .unwrap();
+
+    assert_eq!(report, expected);
+
+    serve.stop();
+}
+
+#[test]
+fn generate_api_key_json_output() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "https://api.balena-cloud.com",
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9"
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = unindent::unindent(
+        r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#,
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let assert = get_base_command()
+        .args(["generate-api-key", "--output", "json"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stderr("`deviceApiKey` already generated\n");
+
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+
+    let expected: serde_json::Value = serde_json::from_str(
+        r#"
+        {
+            "command": "generate-api-key",
+            "generateApiKey": "generatedAlready",
+            "unconfigured": false,
+            "dryRun": false,
+            "cached": false,
+            "serviceConfigChanges": false,
+            "writtenFiles": [],
+            "deletedFiles": [],
+            "migratedKeys": [],
+            "restartedUnits": []
+        }
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(report, expected);
+
+    validate_json_file(&config_json_path, config_json, false);
+}
+
+#[test]
+#[timeout(10000)]
+fn update_unmanaged_detailed_exitcodes() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    get_base_command()
+        .args(["update", "--detailed-exitcodes"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(3)
+        .stdout("Unconfigured device. Exiting...\n");
+
+    validate_json_file(&config_json_path, config_json, false);
+
+    // A mistyped flag is a failure, not the changes code 2
+    get_base_command()
+        .args(["update", "--detailed-exitcodes", "--dry-runn"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(1);
+
+    get_base_command()
+        .args(["--help"])
+        .timeout(Duration::from_secs(5))
+        .assert()
+        .success();
+}
+
+#[test]
+#[timeout(10000)]
+fn join_fetch_failure_exit_code() {
+    let port = 31020;
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    // Nothing is listening on the port
+    let json_config = format!(
+        r#"
+        {{
+            "deviceType": "raspberrypi3",
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let assert = get_base_command()
+        .args(["join", &json_config])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.starts_with("Error: Fetching configuration failed"));
+
+    let assert = get_base_command()
+        .args(["--output", "json", "join", &json_config])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert_eq!(report["command"], "join");
+    assert_eq!(report["error"]["class"], "fetch");
+    assert!(report["error"]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Fetching configuration failed: "));
+
+    validate_json_file(&config_json_path, config_json, false);
+}
+
+#[test]
+#[timeout(10000)]
+fn join_from_file() {
+    let port = 31021;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let json_config = format!(
+        r#"
+        {{
+            "deviceType": "raspberrypi3",
+            "apiEndpoint": "http://{}",
+            "apiKey": "12345678abcd1234efgh1234567890ab"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let json_config_path = create_tmp_file(&tmp_dir, "provisioning.json", &json_config, None);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        No configuration changes
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Writing {tmp_dir_path}/config.json
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["join", "--file", &json_config_path])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_json_file(
+        &config_json_path,
+        &format!(
+            r#"
+            {{
+                "deviceType": "raspberrypi3",
+                "hostname": "balena",
+                "persistentLogging": false,
+                "apiEndpoint": "http://{}",
+                "apiKey": "12345678abcd1234efgh1234567890ab",
+                "deviceApiKeys": {{}}
+            }}
+            "#,
+            server_address(port)
+        ),
+        true,
+    );
+
+    serve.stop();
+}
+
+#[test]
+fn join_from_stdin_incompatible_device_types() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let json_config = r#"
+        {
+            "deviceType": "incompatible-device-type",
+            "apiEndpoint": "http://localhost:31022"
+        }
+        "#;
+
+    let output = unindent::unindent(
+        "
+        Error: Merging `config.json` failed
+
+        Caused by:
+            Expected `deviceType` raspberrypi3, got incompatible-device-type
+        ",
+    );
+
+    get_base_command()
+        .args(["join", "-"])
+        .write_stdin(json_config)
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .failure()
+        .stderr(output);
+
+    validate_json_file(&config_json_path, config_json, false);
+}
+
+#[test]
+#[timeout(10000)]
+fn status_with_path_flags() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "https://api.balena-cloud.com",
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9"
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let output = unindent::unindent(
+        r#"
+        Managed device
+        API endpoint: https://api.balena-cloud.com
+        deviceApiKey: f0f0236...
+        No stored deviceApiKeys
+        "#,
+    );
+
+    let flasher_flag_path = create_tmp_file(&tmp_dir, "balena-image-flasher", "", None);
+
+    // Flags take precedence over the environment variables, an explicit config.json path
+    // over the flasher one as well
+    get_base_command()
+        .args([
+            "status",
+            "--schema",
+            &os_config_path,
+            "--config-json",
+            &config_json_path,
+        ])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(
+            &format!("{tmp_dir_path}/missing-os-config.json"),
+            &format!("{tmp_dir_path}/missing-config.json"),
+        ))
+        .env(
+            CONFIG_JSON_FLASHER_PATH_REDEFINE,
+            format!("{tmp_dir_path}/missing-flasher-config.json"),
+        )
+        .env(FLASHER_FLAG_PATH_REDEFINE, flasher_flag_path)
+        .assert()
+        .success()
+        .stdout(output);
+}
+
+#[test]
+#[timeout(10000)]
+fn status_with_defaults_file() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let defaults = format!(
+        r#"
+        schema = "{os_config_path}"
+        config-json = "{config_json_path}"
+        "#
+    );
+
+    let os_config_toml_path = create_tmp_file(&tmp_dir, "os-config.toml", &defaults, None);
+
+    let output = unindent::unindent(
+        r#"
+        Unconfigured device
+        No stored deviceApiKeys
+        "#,
+    );
+
+    get_base_command()
+        .args(["status"])
+        .timeout(Duration::from_secs(5))
+        .env(OS_CONFIG_TOML_PATH_REDEFINE, &os_config_toml_path)
+        .env(MOCK_SYSTEMD, "1")
+        .assert()
+        .success()
+        .stdout(output);
+}
+
+#[test]
+#[timeout(10000)]
+fn validate_schema() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1-2",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service"]
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let output = format!("{os_config_path} is valid\n");
+
+    get_base_command()
+        .args(["validate", &os_config_path])
+        .timeout(Duration::from_secs(5))
+        .env(MOCK_SYSTEMD, "1")
+        .assert()
+        .success()
+        .stdout(output);
+}
+
+#[test]
+#[timeout(10000)]
+fn validate_invalid_schema() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let schema = r#"
+        {
+            "services": [
+                {
+                    "id": "mock-1-2",
+                    "files": {
+                        "mock-1": {
+                            "path": "mock-1.conf",
+                            "perm": "6OO"
+                        }
+                    },
+                    "systemd_services": ["mock-service-1"]
+                }
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"],
+                "blacklist": []
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        4 problem(s) found in {os_config_path}:
+            services[0].files.mock-1.path: `mock-1.conf` is not an absolute path
+            services[0].files.mock-1.perm: `6OO` is not an octal permission mode
+            services[0].systemd_services[0]: `mock-service-1` has no systemd unit suffix
+            config.blacklist: unknown field
+        "#
+    ));
+
+    get_base_command()
+        .args(["validate"])
+        .timeout(Duration::from_secs(5))
+        .env(OS_CONFIG_PATH_REDEFINE, &os_config_path)
+        .env(MOCK_SYSTEMD, "1")
+        .assert()
+        .code(11)
+        .stdout(output);
+}
+
+#[test]
+#[timeout(10000)]
+fn update_rollback() {
+    let port = 31023;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}",
+            "logsEndpoint": "https://logs.balenadev.io"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1-2",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }},
+                        "mock-2": {{
+                            "path": "{tmp_dir_path}/mock-2.conf",
+                            "perm": "755"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service", "mock-service-2.service"]
+                }},
+                {{
+                    "id": "mock-3",
+                    "files": {{
+                        "mock-3": {{
+                            "path": "{tmp_dir_path}/missing/mock-3.conf",
+                            "perm": ""
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-3.service"]
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    create_tmp_file(&tmp_dir, "mock-1.conf", "MOCK-1-0000000000", Some(0o644));
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1-2": {
+                    "mock-1": "MOCK-1-0123456789",
+                    "mock-2": "MOCK-2-0123456789"
+                },
+                "mock-3": {
+                    "mock-3": "MOCK-3-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {
+                    "logsEndpoint": "https://logs.balena-cloud.com"
+                }
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Key `logsEndpoint` found with existing value `"https://logs.balenadev.io"`, will override to `"https://logs.balena-cloud.com"`
+        Done config.json migrations
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Writing {config_json_path}
+        Stopping mock-service-1.service...
+        Stopping mock-service-2.service...
+        Awaiting mock-service-1.service to exit...
+        Awaiting mock-service-2.service to exit...
+        {tmp_dir_path}/mock-1.conf updated
+        {tmp_dir_path}/mock-2.conf updated
+        Starting mock-service-1.service...
+        Starting mock-service-2.service...
+        Stopping mock-service-3.service...
+        Awaiting mock-service-3.service to exit...
+        Rolling back configuration changes...
+        {config_json_path} restored
+        {tmp_dir_path}/mock-1.conf restored
+        {tmp_dir_path}/mock-2.conf removed
+        Reloading or restarting mock-service-1.service...
+        Reloading or restarting mock-service-2.service...
+        Reloading or restarting mock-service-3.service...
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    let assert = get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(13)
+        .stdout(output);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.starts_with("Error: Configuration changes were rolled back"));
+
+    validate_json_file(&config_json_path, &config_json, false);
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0000000000",
+        Some(0o644),
+    );
+
+    validate_does_not_exist(&format!("{tmp_dir_path}/mock-2.conf"));
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_and_rollback() {
+    let port = 31024;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}",
+            "logsEndpoint": "https://logs.balenadev.io"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }},
+                        "mock-2": {{
+                            "path": "{tmp_dir_path}/mock-2.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service"]
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    create_tmp_file(&tmp_dir, "mock-1.conf", "MOCK-1-0000000000", Some(0o600));
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789",
+                    "mock-2": "MOCK-2-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {
+                    "logsEndpoint": "https://logs.balena-cloud.com"
+                }
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success();
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0123456789",
+        Some(0o600),
+    );
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-2.conf"),
+        "MOCK-2-0123456789",
+        Some(0o600),
+    );
+
+    serve.stop();
+
+    // Keys os-config does not manage, e.g. a key rotated after the update, are kept
+    let rotated = std::fs::read_to_string(&config_json_path)
+        .unwrap()
+        .replace(
+            "f0f0236b70be9a5983d3fd49ac9719b9",
+            "7ba4fb7bd9d1e3ee1e1ff1c4c70d1e3b",
+        )
+        .replace("\"balena\"", "\"renamed\"");
+    std::fs::write(&config_json_path, rotated).unwrap();
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Rolling back to generation 1...
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Writing {tmp_dir_path}/config.json
+        Stopping mock-service-1.service...
+        Awaiting mock-service-1.service to exit...
+        {tmp_dir_path}/mock-1.conf updated
+        {tmp_dir_path}/mock-2.conf deleted
+        Starting mock-service-1.service...
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["rollback"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0000000000",
+        Some(0o600),
+    );
+
+    // The file did not exist before the first update
+    assert!(!std::path::Path::new(&format!("{tmp_dir_path}/mock-2.conf")).exists());
+
+    validate_json_file(
+        &config_json_path,
+        &config_json
+            .replace(
+                "f0f0236b70be9a5983d3fd49ac9719b9",
+                "7ba4fb7bd9d1e3ee1e1ff1c4c70d1e3b",
+            )
+            .replace("\"balena\"", "\"renamed\""),
+        false,
+    );
+
+    // There is nothing older than the baseline generation
+    let assert = get_base_command()
+        .args(["rollback"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .failure();
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.starts_with("Error: No previous generation to roll back to"));
+}
+
+#[test]
+#[timeout(10000)]
+fn daemon() {
+    let port = 31025;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service"]
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let mut child = get_base_std_command()
+        .args(["daemon", "--interval", "60", "--jitter", "0"])
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let pid = child.id().to_string();
+    let signal = |name: &str| {
+        std::process::Command::new("kill")
+            .args(["-s", name, &pid])
+            .status()
+            .unwrap();
+    };
+
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+
+    // The handlers are installed once the daemon announces its interval
+    let mut output = read_until(&mut lines, "Checking for configuration updates");
+    signal("HUP");
+    output.extend(read_until(&mut lines, "SIGHUP received"));
+    output.extend(read_until(&mut lines, "No configuration changes"));
+    signal("TERM");
+    output.extend(lines.map(Result::unwrap));
+
+    let expected = unindent::unindent(&format!(
+        r#"
+        Checking for configuration updates every 60s
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Stopping mock-service-1.service...
+        Awaiting mock-service-1.service to exit...
+        {tmp_dir_path}/mock-1.conf updated
+        Starting mock-service-1.service...
+        Starting balena-supervisor.service...
+        SIGHUP received, checking now
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        No configuration changes
+        Shutting down...
+        "#
+    ));
+
+    assert!(child.wait().unwrap().success());
+    assert_eq!(output.join("\n") + "\n", expected);
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0123456789",
+        Some(0o600),
+    );
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn daemon_terminate_while_retrying() {
+    let port = 31047;
+    let tmp_dir = TempDir::new().unwrap();
+
+    // Nothing is listening on the port
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    // Without a limit the backoff alone would outlast the test timeout
+    let mut child = get_base_std_command()
+        .args(["daemon", "--retry-attempts", "0"])
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+
+    read_until(&mut lines, "error sending request");
+
+    std::process::Command::new("kill")
+        .args(["-s", "TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+
+    let read = read_until(&mut lines, "Checking for configuration updates failed");
+    assert!(read
+        .last()
+        .unwrap()
+        .contains("Terminating, stopped retrying"));
+    read_until(&mut lines, "Shutting down...");
+
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+#[timeout(10000)]
+fn update_unauthorized() {
+    let port = 31026;
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let mut serve = serve_response(401, &[], "Unauthorized".into(), false, port);
+
+    let output =
+        format!("Fetching service configuration from http://localhost:{port}/os/v1/config...\n");
+
+    // Not retried, as a bad deviceApiKey will not fix itself
+    let assert = get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10)
+        .stdout(output);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.starts_with("Error: Fetching configuration failed"));
+    assert!(stderr.contains("Unauthorized, check deviceApiKey: Unauthorized"));
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn join_server_error() {
+    let port = 31027;
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let mut serve = serve_response(
+        503,
+        &[("Retry-After", "30")],
+        "<html>\n<body>Down for maintenance</body>\n</html>".into(),
+        false,
+        port,
+    );
+
+    let json_config = format!(
+        r#"
+        {{
+            "deviceType": "raspberrypi3",
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let assert = get_base_command()
+        .args(["join", &json_config])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.contains(
+        "Server error 503 Service Unavailable: <html> <body>Down for maintenance</body> </html>"
+    ));
+
+    validate_json_file(&config_json_path, config_json, false);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_retry_gives_up() {
+    let port = 31028;
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            },
+            "retry": {
+                "max_attempts": 5,
+                "max_backoff": 1
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let mut serve = serve_response(503, &[], "busy".into(), false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Server error 503 Service Unavailable: busy
+        "#
+    ));
+
+    // The command line takes precedence over the schema
+    let assert = get_base_command()
+        .args(["update", "--retry-attempts", "2"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10)
+        .stdout(output);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.starts_with("Error: Fetching configuration failed"));
+    assert!(stderr.contains("Gave up after 2 attempts / "));
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_not_modified() {
+    let port = 31029;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service"]
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_response(200, &[("ETag", "\"v1\"")], configuration, false, port);
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success();
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0123456789",
+        Some(0o600),
+    );
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration not modified
+        Checking for config.json migrations...
+        No configuration changes
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_from_cache() {
+    let port = 31030;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": ["mock-service-1.service"]
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success();
+
+    serve.stop();
+
+    remove_file(format!("{tmp_dir_path}/mock-1.conf")).unwrap();
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Using cached service configuration
+        Checking for config.json migrations...
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Stopping mock-service-1.service...
+        Awaiting mock-service-1.service to exit...
+        {tmp_dir_path}/mock-1.conf updated
+        Starting mock-service-1.service...
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["update", "--offline"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0123456789",
+        Some(0o600),
+    );
+
+    remove_file(format!("{tmp_dir_path}/mock-1.conf")).unwrap();
+
+    // The API is gone, so the fetch falls back to the cache once the retries run out
+    let assert = get_base_command()
+        .args(["update", "--retry-attempts", "1"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success();
+
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    assert!(stdout.contains("Falling back to cached service configuration\n"));
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0123456789",
+        Some(0o600),
+    );
+
+    // An API that refuses the device is reported rather than worked around with the cache
+    let mut serve = serve_response(401, &[], "Unauthorized".into(), false, port);
+
+    let assert = get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    assert!(!stdout.contains("Falling back to cached service configuration"));
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_signed() {
+    let port = 31031;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": []
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }},
+            "signing_key": {}
+        }}
+        "#,
+        serde_json::to_string(&signing_key()).unwrap()
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let signature = sign_config(&configuration);
+
+    let mut serve = serve_response(
+        200,
+        &[("X-Config-Signature", &signature)],
+        configuration,
+        false,
+        port,
+    );
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Service configuration signature verified
+        Checking for config.json migrations...
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        {tmp_dir_path}/mock-1.conf updated
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0123456789",
+        Some(0o600),
+    );
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_bad_signature() {
+    let port = 31032;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}",
+            "balenaConfigSigningKey": "{}"
+        }}
+        "#,
+        server_address(port),
+        cert_for_json(&signing_key())
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": []
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    create_tmp_file(&tmp_dir, "mock-1.conf", "MOCK-1-0000000000", Some(0o600));
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    // Signed before the payload got tampered with on the way
+    let signature = sign_config(&configuration.replace("0123456789", "9876543210"));
+
+    let mut serve = serve_response(
+        200,
+        &[("X-Config-Signature", &signature)],
+        configuration,
+        false,
+        port,
+    );
+
+    let assert = get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.contains("Verifying service configuration signature failed"));
+    assert!(stderr.contains("Signature does not match"));
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0000000000",
+        Some(0o600),
+    );
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_with_client_certificate() {
+    let port = 31033;
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "https://{}",
+            "balenaRootCA": "{}",
+            "balenaClientCert": "{}",
+            "balenaClientKey": "{}"
+        }}
+        "#,
+        server_address(port),
+        cert_for_json(CERTIFICATE),
+        cert_for_json(CERTIFICATE),
+        cert_for_json(RSA_PRIVATE_KEY)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config_mutual_tls(configuration, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from https://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        No configuration changes
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    // Without the client certificate the server refuses the handshake
+    let other_dir = TempDir::new().unwrap();
+
+    let mut config_json: serde_json::Value = serde_json::from_str(&config_json).unwrap();
+    let config = config_json.as_object_mut().unwrap();
+    config.remove("balenaClientCert");
+    config.remove("balenaClientKey");
+
+    let config_json_path =
+        create_tmp_file(&other_dir, "config.json", &config_json.to_string(), None);
+
+    let os_config_path = create_tmp_file(&other_dir, "os-config.json", schema, None);
+
+    get_base_command()
+        .args(["update", "--retry-attempts", "1"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_with_api_pins() {
+    let port = 31034;
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, true, port);
+
+    let config_json = |pin: &str| {
+        format!(
+            r#"
+            {{
+                "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+                "deviceType": "raspberrypi3",
+                "hostname": "balena",
+                "persistentLogging": false,
+                "apiEndpoint": "https://{}",
+                "balenaRootCA": "{}",
+                "balenaApiPins": ["sha256/{}"]
+            }}
+            "#,
+            server_address(port),
+            cert_for_json(CERTIFICATE),
+            pin
+        )
+    };
+
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json_path =
+        create_tmp_file(&tmp_dir, "config.json", &config_json(CERTIFICATE_PIN), None);
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from https://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        No configuration changes
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    let other_dir = TempDir::new().unwrap();
+
+    let other_pin = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+    let config_json_path =
+        create_tmp_file(&other_dir, "config.json", &config_json(other_pin), None);
+    let os_config_path = create_tmp_file(&other_dir, "os-config.json", schema, None);
+
+    // A pin mismatch is not retried
+    let assert = get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.contains(&format!(
+        "Public key pin mismatch, server key sha256/{CERTIFICATE_PIN} is not listed in `balenaApiPins`"
+    )));
+
+    // The mismatch fails the handshake, so the API key never reached the server
+    assert_eq!(
+        serve.authorizations(),
+        vec!["Bearer f0f0236b70be9a5983d3fd49ac9719b9"]
+    );
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_through_proxy() {
+    let port = 31035;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    // Only reachable through the proxy
+    let config_json = r#"
+        {
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://api.balena-cloud.invalid"
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    // The boot partition is not where config.json lives
+    let boot_dir = format!("{tmp_dir_path}/boot");
+    std::fs::create_dir_all(format!("{boot_dir}/system-proxy")).unwrap();
+
+    let redsocks_conf = format!(
+        r#"
+        redsocks {{
+            type = http-relay;
+            ip = 127.0.0.1;
+            port = {port};
+            local_ip = 127.0.0.1;
+            local_port = 12345;
+        }}
+        "#
+    );
+
+    create_tmp_file(
+        &tmp_dir,
+        "boot/system-proxy/redsocks.conf",
+        &redsocks_conf,
+        None,
+    );
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    // The mock server answers the proxied request for the configuration route
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(
+        r#"
+        Fetching service configuration from http://api.balena-cloud.invalid/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        No configuration changes
+        "#,
+    );
+
+    get_base_command()
+        .args(["update", "--boot-dir", &boot_dir])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output.clone());
+
+    remove_file(format!("{boot_dir}/system-proxy/redsocks.conf")).unwrap();
+
+    get_base_command()
+        .args([
+            "update",
+            "--proxy",
+            &format!("http://{}", server_address(port)),
+        ])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_from_mirror() {
+    let port = 31036;
+    let mirror_port = 31037;
+    let unkeyed_mirror_port = 31046;
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceApiKeys": {{
+                "{}": "0f0f236b70be9a5983d3fd49ac9719b9"
+            }},
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}",
+            "apiMirrors": ["http://{}", "http://{}"]
+        }}
+        "#,
+        server_address(mirror_port),
+        server_address(port),
+        server_address(unkeyed_mirror_port),
+        server_address(mirror_port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_response(503, &[], "down".into(), false, port);
+    let mut serve_unkeyed_mirror = serve_config(configuration.clone(), false, unkeyed_mirror_port);
+    let mut serve_mirror = serve_config(configuration, false, mirror_port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Skipping mirror http://localhost:{unkeyed_mirror_port} without a key of its own in `deviceApiKeys`
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved from mirror http://localhost:{mirror_port}
+        Checking for config.json migrations...
+        No configuration changes
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    // Mirrors only ever get keys of their own
+    assert!(serve_unkeyed_mirror.authorizations().is_empty());
+    assert_eq!(
+        serve_mirror.authorizations(),
+        vec!["Bearer 0f0f236b70be9a5983d3fd49ac9719b9"]
+    );
+
+    serve.stop();
+    serve_unkeyed_mirror.stop();
+    serve_mirror.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_pin_mismatch_skips_mirrors() {
+    let port = 31042;
+    let mirror_port = 31043;
+    let tmp_dir = TempDir::new().unwrap();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "https://{}",
+            "balenaRootCA": "{}",
+            "balenaApiPins": ["sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="],
+            "apiMirrors": ["http://{}"]
+        }}
+        "#,
+        server_address(port),
+        cert_for_json(CERTIFICATE),
+        server_address(mirror_port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration.clone(), true, port);
+    let mut serve_mirror = serve_config(configuration, false, mirror_port);
+
+    let assert = get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(stderr.contains("Public key pin mismatch"));
+
+    // An intercepted primary endpoint fails the fetch instead of falling back to the mirrors
+    assert!(serve.authorizations().is_empty());
+    assert!(serve_mirror.authorizations().is_empty());
+
+    serve.stop();
+    serve_mirror.stop();
+}
+
+#[test]
+fn update_from_local_bundle() {
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    // Air-gapped devices have no API configured at all
+    let config_json = r#"
+        {
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false
+        }
+        "#;
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": []
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }},
+            "signing_key": {}
+        }}
+        "#,
+        serde_json::to_string(&signing_key()).unwrap()
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let bundle_dir = TempDir::new().unwrap();
+    let bundle_dir_path = bundle_dir.path().to_str().unwrap().to_string();
+    // The signature covers the exact bytes, so write the bundle as is
+    std::fs::write(bundle_dir.path().join("os-config-api.json"), &configuration).unwrap();
+    std::fs::write(
+        bundle_dir.path().join("os-config-api.json.sig"),
+        sign_config(&configuration),
+    )
+    .unwrap();
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Reading service configuration from {bundle_dir_path}/os-config-api.json...
+        Service configuration retrieved
+        Service configuration signature verified
+        Checking for config.json migrations...
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        {tmp_dir_path}/mock-1.conf updated
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["update", "--source", &format!("file://{bundle_dir_path}")])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_file(
+        &format!("{tmp_dir_path}/mock-1.conf"),
+        "MOCK-1-0123456789",
+        Some(0o600),
+    );
+}
+
+#[test]
+#[timeout(10000)]
+fn update_reports_state() {
+    let port = 31038;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "mock-1": {{
+                            "path": "{tmp_dir_path}/mock-1.conf",
+                            "perm": ""
+                        }}
+                    }},
+                    "systemd_services": []
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "mock-1": "MOCK-1-0123456789"
+                }
+            },
+            "config": {
+                "overrides": {
+                    "logsEndpoint": "https://logs.balenadev.io"
+                }
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Key `logsEndpoint` not found, will insert `"https://logs.balenadev.io"`
+        Done config.json migrations
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Writing {tmp_dir_path}/config.json
+        {tmp_dir_path}/mock-1.conf updated
+        Starting balena-supervisor.service...
+        Reporting configuration state to http://localhost:{port}{STATE_ROUTE}...
+        Configuration state reported
+        "#
+    ));
+
+    get_base_command()
+        .args(["--state-route", STATE_ROUTE, "update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    let reports = serve.state_reports();
+    assert_eq!(reports.len(), 1);
+
+    let state: serde_json::Value = serde_json::from_str(&reports[0]).unwrap();
+
+    let expected = serde_json::json!({
+        "services": {
+            "mock-1": {
+                "mock-1": "2e27a0c02209fe8f1277889ef7fe8d4d73e0fa2bec1b591fe8daae2912cfdabe"
+            }
+        },
+        "migratedKeys": ["logsEndpoint"],
+        "generation": 2,
+        "error": null
+    });
+
+    assert_eq!(state, expected);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_binary_file() {
+    let port = 31039;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = format!(
+        r#"
+        {{
+            "services": [
+                {{
+                    "id": "mock-1",
+                    "files": {{
+                        "keytab": {{
+                            "path": "{tmp_dir_path}/mock-1.keytab",
+                            "perm": "600"
+                        }}
+                    }},
+                    "systemd_services": []
+                }}
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {{
+                "whitelist": ["logsEndpoint"]
+            }}
+        }}
+        "#
+    );
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", &schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+                "mock-1": {
+                    "keytab": {
+                        "encoding": "base64",
+                        "content": "BQIAAABA/w=="
+                    }
+                }
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        {tmp_dir_path}/mock-1.keytab updated
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    assert_eq!(
+        std::fs::read(format!("{tmp_dir_path}/mock-1.keytab")).unwrap(),
+        vec![0x05, 0x02, 0x00, 0x00, 0x00, 0x40, 0xff]
+    );
+
+    // The same bytes are no change
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        No configuration changes
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_deletes_config_json_keys() {
+    let port = 31040;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "logsEndpoint": "https://logs.resin.io",
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {},
+                "deletes": ["logsEndpoint", "hostname"]
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Key `hostname` not in whitelist, skipping
+        Key `logsEndpoint` found with existing value `"https://logs.resin.io"`, will delete
+        Done config.json migrations
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Writing {tmp_dir_path}/config.json
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_json_file(
+        &config_json_path,
+        &format!(
+            r#"
+            {{
+                "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+                "deviceType": "raspberrypi3",
+                "hostname": "balena",
+                "apiEndpoint": "http://{}"
+            }}
+            "#,
+            server_address(port)
+        ),
+        false,
+    );
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_pointer_override() {
+    let port = 31041;
+    let tmp_dir = TempDir::new().unwrap();
+    let tmp_dir_path = tmp_dir.path().to_str().unwrap().to_string();
+
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "os": {{
+                "sshKeys": ["ssh-ed25519 AAAA"],
+                "udevRules": {{
+                    "10": "old rule"
+                }}
+            }},
+            "apiEndpoint": "http://{}"
+        }}
+        "#,
+        server_address(port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["/os/udevRules"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {
+                    "/os/udevRules/56": "new rule",
+                    "/os/sshKeys/0": "ssh-ed25519 BBBB"
+                }
+            }
+        }
+        "#,
+    );
+
+    let mut serve = serve_config(configuration, false, port);
+
+    let output = unindent::unindent(&format!(
+        r#"
+        Fetching service configuration from http://localhost:{port}/os/v1/config...
+        Service configuration retrieved
+        Checking for config.json migrations...
+        Key `/os/sshKeys/0` not in whitelist, skipping
+        Key `/os/udevRules/56` not found, will insert `"new rule"`
+        Done config.json migrations
+        Stopping balena-supervisor.service...
+        Awaiting balena-supervisor.service to exit...
+        Writing {tmp_dir_path}/config.json
+        Starting balena-supervisor.service...
+        "#
+    ));
+
+    get_base_command()
+        .args(["update"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .success()
+        .stdout(output);
+
+    validate_json_file(
+        &config_json_path,
+        &format!(
+            r#"
+            {{
+                "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+                "deviceType": "raspberrypi3",
+                "hostname": "balena",
+                "os": {{
+                    "sshKeys": ["ssh-ed25519 AAAA"],
+                    "udevRules": {{
+                        "10": "old rule",
+                        "56": "new rule"
+                    }}
+                }},
+                "apiEndpoint": "http://{}"
+            }}
+            "#,
+            server_address(port)
+        ),
+        false,
+    );
+
+    serve.stop();
+}
+
+#[test]
+#[timeout(10000)]
+fn update_skips_unpinned_mirrors() {
+    let port = 31044;
+    let mirror_port = 31045;
+    let tmp_dir = TempDir::new().unwrap();
+
+    // Nothing listens on the pinned endpoint
+    let config_json = format!(
+        r#"
+        {{
+            "deviceApiKey": "f0f0236b70be9a5983d3fd49ac9719b9",
+            "deviceType": "raspberrypi3",
+            "hostname": "balena",
+            "persistentLogging": false,
+            "apiEndpoint": "https://{}",
+            "balenaRootCA": "{}",
+            "balenaApiPins": ["sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="],
+            "apiMirrors": ["http://{}"]
+        }}
+        "#,
+        server_address(port),
+        cert_for_json(CERTIFICATE),
+        server_address(mirror_port)
+    );
+
+    let config_json_path = create_tmp_file(&tmp_dir, "config.json", &config_json, None);
+
+    let schema = r#"
+        {
+            "services": [
+            ],
+            "keys": ["apiKey", "apiEndpoint", "vpnEndpoint"],
+            "config": {
+                "whitelist": ["logsEndpoint"]
+            }
+        }
+        "#;
+
+    let os_config_path = create_tmp_file(&tmp_dir, "os-config.json", schema, None);
+
+    let configuration = unindent::unindent(
+        r#"
+        {
+            "services": {
+            },
+            "config": {
+                "overrides": {}
+            }
+        }
+        "#,
+    );
+
+    let mut serve_mirror = serve_config(configuration, false, mirror_port);
+
+    let assert = get_base_command()
+        .args(["update", "--retry-attempts", "1"])
+        .timeout(Duration::from_secs(5))
+        .envs(os_config_env(&os_config_path, &config_json_path))
+        .assert()
+        .code(10);
+
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    assert!(stdout.contains(&format!(
+        "Skipping mirror http://{} without `balenaApiPins` of its own",
+        server_address(mirror_port)
+    )));
+
+    assert!(serve_mirror.authorizations().is_empty());
+
+    serve_mirror.stop();
+}
 /*******************************************************************************
 *  os-config launch
 */
 
-fn os_config_env<'a>(
-    os_config_path: &'a str,
-    config_json_path: &'a str,
-) -> Vec<(&'static str, &'a str)> {
+fn os_config_env(os_config_path: &str, config_json_path: &str) -> Vec<(&'static str, String)> {
     vec![
-        (OS_CONFIG_PATH_REDEFINE, os_config_path),
-        (CONFIG_JSON_PATH_REDEFINE, config_json_path),
-        (MOCK_SYSTEMD, "1"),
+        (OS_CONFIG_PATH_REDEFINE, os_config_path.into()),
+        (CONFIG_JSON_PATH_REDEFINE, config_json_path.into()),
+        (STATE_DIR_REDEFINE, state_dir(config_json_path)),
+        (BOOT_DIR_REDEFINE, boot_dir(config_json_path)),
+        (MOCK_SYSTEMD, "1".into()),
     ]
 }
 
+// Reads the output of a running os-config up to and including the first line containing `needle`
+fn read_until(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+    needle: &str,
+) -> Vec<String> {
+    let mut read = vec![];
+    for line in lines {
+        let line = line.unwrap();
+        let found = line.contains(needle);
+        read.push(line);
+        if found {
+            return read;
+        }
+    }
+
+    panic!("Output ended before a line containing `{needle}`");
+}
+
+// Keep the generations next to the temporary config.json of the test
+fn state_dir(config_json_path: &str) -> String {
+    let parent = std::path::Path::new(config_json_path).parent().unwrap();
+    parent.join("os-config").to_str().unwrap().into()
+}
+
+// The temporary directory of the test stands in for the boot partition
+fn boot_dir(config_json_path: &str) -> String {
+    let parent = std::path::Path::new(config_json_path).parent().unwrap();
+    parent.to_str().unwrap().into()
+}
+
 /*******************************************************************************
 *  Ability to run under `cross`. Borrowed from:
 *  https://github.com/assert-rs/assert_cmd/issues/139#issuecomment-1200146157
@@ -2084,17 +5349,22 @@ fn find_runner() -> Option<String> {
 }
 
 fn get_base_command() -> Command {
+    Command::from_std(get_base_std_command())
+}
+
+// For tests that need to signal a running process
+fn get_base_std_command() -> std::process::Command {
     let mut cmd;
     let path = assert_cmd::cargo::cargo_bin("os-config");
     if let Some(runner) = find_runner() {
         let mut runner = runner.split_whitespace();
-        cmd = Command::new(runner.next().unwrap());
+        cmd = std::process::Command::new(runner.next().unwrap());
         for arg in runner {
             cmd.arg(arg);
         }
         cmd.arg(path);
     } else {
-        cmd = Command::new(path);
+        cmd = std::process::Command::new(path);
     }
     cmd
 }